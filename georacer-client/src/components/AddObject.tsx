@@ -3,6 +3,16 @@ import React, { useState, useRef } from 'react';
 import CameraView from './CameraView';
 import config from '../config';
 
+// Resolves to null when the browser can't or won't share a position
+const currentPosition = (): Promise<GeolocationPosition | null> =>
+    new Promise((resolve) => {
+        if (!navigator.geolocation) return resolve(null);
+        navigator.geolocation.getCurrentPosition(resolve, () => resolve(null), {
+            enableHighAccuracy: true,
+            timeout: 10000,
+        });
+    });
+
 const AddObject: React.FC = () => {
     const [name, setName] = useState('');
     const cameraRef = useRef<{ takePicture: () => string | null }>(null);
@@ -11,13 +21,21 @@ const AddObject: React.FC = () => {
         if (cameraRef.current) {
             const image = cameraRef.current.takePicture();
             if (image) {
+                const position = await currentPosition();
+                const location = position && {
+                    location: {
+                        type: 'Point',
+                        coordinates: [position.coords.longitude, position.coords.latitude],
+                    },
+                    accuracy_m: position.coords.accuracy,
+                };
                 try {
-                    const response = await fetch(`http://${config.apiUrl}/gameobject/image`, {
+                    const response = await fetch(`http://${config.apiUrl}/register`, {
                         method: 'POST',
                         headers: {
                             'Content-Type': 'application/json',
                         },
-                        body: JSON.stringify({ name, image }),
+                        body: JSON.stringify({ name, image_b64: image, ...location }),
                     });
                     if (response.ok) {
                         alert('Object saved successfully!');
@@ -0,0 +1,150 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use dashmap::DashMap;
+use image::imageops::FilterType;
+use ort::session::Session;
+use ort::value::Tensor;
+
+use crate::local::env_parse;
+use crate::verifier::{ImageVerifier, Verdict, image_hash};
+use crate::zoom::decode_image;
+
+/// Per-channel normalisation the CLIP image encoders were trained with
+const CLIP_MEAN: [f32; 3] = [0.481_454_66, 0.457_827_5, 0.408_210_73];
+const CLIP_STD: [f32; 3] = [0.268_629_54, 0.261_302_6, 0.275_777_1];
+
+/// Where the model lives and how close two embeddings must be to match
+#[derive(Debug, Clone)]
+pub struct ClipConfig {
+    /// ONNX image encoder taking `[1, 3, input_size, input_size]` and returning `[1, dim]`
+    pub model_path: PathBuf,
+    /// Side of the square the encoder expects
+    pub input_size: u32,
+    /// Cosine similarity at or above which two images match
+    pub min_similarity: f32,
+    /// CPU threads a single inference may use
+    pub threads: usize,
+}
+
+impl ClipConfig {
+    /// Read `CLIP_MODEL_PATH` (required) and, when set, `CLIP_INPUT_SIZE`,
+    /// `CLIP_MIN_SIMILARITY` and `CLIP_THREADS`
+    pub fn from_env() -> anyhow::Result<Self> {
+        let model_path = dotenvy::var("CLIP_MODEL_PATH").context("IMAGE_VERIFIER=clip needs CLIP_MODEL_PATH")?;
+        Ok(Self {
+            model_path: model_path.into(),
+            input_size: env_parse("CLIP_INPUT_SIZE").unwrap_or(224),
+            min_similarity: env_parse("CLIP_MIN_SIMILARITY").unwrap_or(0.85),
+            threads: env_parse("CLIP_THREADS").unwrap_or(2),
+        })
+    }
+}
+
+/// The loaded image encoder. Runs one inference at a time.
+pub struct ClipModel {
+    session: Mutex<Session>,
+    input_size: u32,
+}
+
+impl ClipModel {
+    pub fn load(config: &ClipConfig) -> anyhow::Result<Self> {
+        let session = Session::builder()?
+            .with_intra_threads(config.threads)?
+            .commit_from_file(&config.model_path)
+            .with_context(|| format!("loading CLIP model {}", config.model_path.display()))?;
+        Ok(Self { session: Mutex::new(session), input_size: config.input_size })
+    }
+
+    /// Unit-length embedding of a (possibly data URL) base64 image. CPU-bound; call
+    /// off the async runtime.
+    pub fn embed_blocking(&self, image_b64: &str) -> anyhow::Result<Vec<f32>> {
+        let size = self.input_size;
+        let rgb = decode_image(image_b64)?.resize_to_fill(size, size, FilterType::CatmullRom).to_rgb8();
+
+        // HWC bytes to normalised CHW floats
+        let plane = (size * size) as usize;
+        let mut pixels = vec![0.0; 3 * plane];
+        for (i, pixel) in rgb.pixels().enumerate() {
+            for channel in 0..3 {
+                pixels[channel * plane + i] = (pixel.0[channel] as f32 / 255.0 - CLIP_MEAN[channel]) / CLIP_STD[channel];
+            }
+        }
+        let input = Tensor::from_array(([1usize, 3, size as usize, size as usize], pixels))?;
+
+        let mut session = self.session.lock().unwrap();
+        let outputs = session.run(ort::inputs![input])?;
+        let (_, embedding) = outputs[0].try_extract_tensor::<f32>().context("reading embedding")?;
+
+        let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt().max(f32::EPSILON);
+        Ok(embedding.iter().map(|x| x / norm).collect())
+    }
+
+    pub async fn embed(self: &Arc<Self>, image_b64: &str) -> anyhow::Result<Vec<f32>> {
+        let model = Arc::clone(self);
+        let image_b64 = image_b64.to_string();
+        tokio::task::spawn_blocking(move || model.embed_blocking(&image_b64))
+            .await
+            .context("embedding task panicked")?
+    }
+}
+
+/// Verifies guesses by embedding both images with a local CLIP-style model and
+/// comparing cosine similarity. Target embeddings are kept, keyed by image hash,
+/// so each target is only embedded once.
+pub struct ClipVerifier {
+    model: Arc<ClipModel>,
+    min_similarity: f32,
+    target_embeddings: DashMap<String, Arc<Vec<f32>>>,
+}
+
+impl ClipVerifier {
+    pub fn new(config: &ClipConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            model: Arc::new(ClipModel::load(config)?),
+            min_similarity: config.min_similarity,
+            target_embeddings: DashMap::new(),
+        })
+    }
+
+    pub fn from_env() -> anyhow::Result<Self> {
+        Self::new(&ClipConfig::from_env()?)
+    }
+
+    /// Embedding of a target image, computed on first use
+    pub async fn target_embedding(&self, target_b64: &str) -> anyhow::Result<Arc<Vec<f32>>> {
+        let key = image_hash(target_b64);
+        if let Some(embedding) = self.target_embeddings.get(&key) {
+            return Ok(Arc::clone(&embedding));
+        }
+        let embedding = Arc::new(self.model.embed(target_b64).await?);
+        self.target_embeddings.insert(key, Arc::clone(&embedding));
+        Ok(embedding)
+    }
+}
+
+#[async_trait]
+impl ImageVerifier for ClipVerifier {
+    async fn verify(&self, target_b64: &str, submission_b64: &str) -> anyhow::Result<Verdict> {
+        let target = self.target_embedding(target_b64).await?;
+        let submission = self.model.embed(submission_b64).await?;
+        anyhow::ensure!(target.len() == submission.len(), "embedding sizes differ");
+
+        let similarity: f32 = target.iter().zip(&submission).map(|(a, b)| a * b).sum();
+        let threshold = self.min_similarity;
+        let matched = similarity >= threshold;
+        // 0.5 right at the threshold, towards 1 the further past it
+        let margin = if matched {
+            (similarity - threshold) / (1.0 - threshold).max(f32::EPSILON)
+        } else {
+            (threshold - similarity) / (threshold + 1.0).max(f32::EPSILON)
+        };
+        Ok(Verdict {
+            matched,
+            confidence: 0.5 + 0.5 * margin.clamp(0.0, 1.0),
+            reason: format!("embeddings {similarity:.2} similar"),
+        })
+    }
+}
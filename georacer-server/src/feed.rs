@@ -1,21 +1,47 @@
-use futures_util::StreamExt;
-use mongodb::bson::{self, doc};
 use mongodb::Database;
 use tokio::sync::{broadcast, RwLock};
 
-use crate::models::{GameObject, ServerMessage};
+use crate::models::{GameObject, GeoPoint, SearchArea, ServerMessage};
+use crate::targets::{self, TargetPool};
+
+/// Parse regional feeds from a spec like `downtown=-122.41,37.77,2000;campus=...`,
+/// where each region is a name, a centre longitude and latitude, and a radius in metres
+pub fn parse_regions(spec: &str) -> Result<Vec<(String, SearchArea)>, String> {
+    spec.split(';')
+        .map(str::trim)
+        .filter(|region| !region.is_empty())
+        .map(|region| {
+            let (name, area) = region.split_once('=').ok_or_else(|| format!("region {region:?} has no '='"))?;
+            let numbers: Vec<f64> = area
+                .split(',')
+                .map(|n| n.trim().parse::<f64>())
+                .collect::<Result<_, _>>()
+                .map_err(|_| format!("region {name:?} must be lng,lat,radius_m"))?;
+            let [lng, lat, radius_m] = numbers[..] else {
+                return Err(format!("region {name:?} must be lng,lat,radius_m"));
+            };
+            let area = SearchArea { centre: GeoPoint::new(lng, lat), radius_m };
+            area.validate().map_err(|e| format!("region {name:?}: {e}"))?;
+            Ok((name.trim().to_string(), area))
+        })
+        .collect()
+}
 
 #[derive(Clone)]
 pub struct Feed {
     tx: broadcast::Sender<ServerMessage>,
     db: Database,
+    /// Playlist targets are drawn from; `None` uses every object
+    playlist_id: Option<String>,
+    /// Region targets are drawn from; `None` is anywhere
+    area: Option<SearchArea>,
     current: std::sync::Arc<RwLock<Option<GameObject>>>,
 }
 
 impl Feed {
-    pub fn new(db: Database) -> Self {
+    pub fn new(db: Database, playlist_id: Option<String>, area: Option<SearchArea>) -> Self {
         let (tx, _) = broadcast::channel(100);
-        Self { tx, db, current: std::sync::Arc::new(RwLock::new(None)) }
+        Self { tx, db, playlist_id, area, current: std::sync::Arc::new(RwLock::new(None)) }
     }
 
     pub fn subscribe(&self) -> broadcast::Receiver<ServerMessage> {
@@ -29,38 +55,33 @@ impl Feed {
     pub fn spawn_loop(self: &std::sync::Arc<Self>, period_secs: u64) {
         let slf = std::sync::Arc::clone(self);
         tokio::spawn(async move {
+            // Shuffle bag: every object is shown once before any repeats
+            let mut used = Vec::new();
             loop {
-                // Pick a random target from MongoDB
-                let coll = slf.db.collection::<GameObject>("gameobjects");
-                let pipeline = vec![doc! { "$sample": { "size": 1 } }];
-
-                match coll.aggregate(pipeline).await {
-                    Ok(mut cursor) => {
-                        if let Some(result) = cursor.next().await {
-                            if let Ok(doc) = result {
-                                match bson::from_document::<GameObject>(doc) {
-                                    Ok(target) => {
-                                        {
-                                            let mut w = slf.current.write().await;
-                                            *w = Some(target.clone());
-                                        }
-                                        tracing::info!(
-                                            target_name = %target.name,
-                                            receivers = slf.tx.receiver_count(),
-                                            "Selected new target for broadcast"
-                                        );
-                                        let _ = slf.tx.send(ServerMessage::Guess { target });
-                                    }
-                                    Err(e) => {
-                                        tracing::error!("Failed to deserialize GameObject: {:?}", e);
-                                    }
-                                }
-                            }
-                        } else {
-                            tracing::warn!("No gameobjects found to sample from");
+                let pool = TargetPool { playlist_id: slf.playlist_id.as_deref(), area: slf.area, hop: None, used: &used };
+                match targets::pick_target(&slf.db, &pool).await {
+                    Ok(Some(target)) => {
+                        if let Some(id) = target.id {
+                            used.push(id.to_hex());
                         }
+                        {
+                            let mut w = slf.current.write().await;
+                            *w = Some(target.clone());
+                        }
+                        tracing::info!(
+                            target_name = %target.name,
+                            receivers = slf.tx.receiver_count(),
+                            "Selected new target for broadcast"
+                        );
+                        let _ = slf.tx.send(ServerMessage::Guess { target: target.for_searching() });
+                    }
+                    Ok(None) if !used.is_empty() => {
+                        // Everything has been shown; refill the bag and draw again straight away
+                        used.clear();
+                        continue;
                     }
-                    Err(e) => tracing::error!("Mongo aggregate error: {:?}", e),
+                    Ok(None) => tracing::warn!("No gameobjects found to sample from"),
+                    Err(e) => tracing::error!("{}", e),
                 }
 
                 tokio::time::sleep(std::time::Duration::from_secs(period_secs)).await;
@@ -1,7 +1,13 @@
-use gemini_rust::{Gemini, Model};
+use anyhow::Context;
+use async_trait::async_trait;
+use gemini_rust::{ClientError, Gemini, Model};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use serde::{Deserialize, Serialize};
 use tracing::trace;
 
+use crate::verifier::{ImageVerifier, Verdict};
+
 // Request structs
 #[derive(Serialize)]
 pub struct GeminiRequest {
@@ -49,34 +55,274 @@ pub struct ResponsePart {
     pub text: String,
 }
 
-fn parse_data_url(s: &str) -> (String, String) {
-    if let Some(prefix) = s.strip_prefix("data:") {
-        if let Some(idx) = prefix.find(',') {
-            let meta = &prefix[..idx]; // e.g. image/jpeg;base64
-            let data = &prefix[idx + 1..];
-            let mime = meta.split(';').next().unwrap_or("image/jpeg").to_string();
-            return (mime, data.to_string());
-        }
+pub(crate) fn parse_data_url(s: &str) -> (String, String) {
+    if let Some(prefix) = s.strip_prefix("data:")
+        && let Some(idx) = prefix.find(',')
+    {
+        let meta = &prefix[..idx]; // e.g. image/jpeg;base64
+        let data = &prefix[idx + 1..];
+        let mime = meta.split(';').next().unwrap_or("image/jpeg").to_string();
+        return (mime, data.to_string());
     }
     ("image/jpeg".to_string(), s.to_string())
 }
 
-pub async fn is_same_image(image1_b64: &str, image2_b64: &str) -> Result<bool, reqwest::Error> {
-    let api_key = std::env::var("GEMINI_API_KEY").expect("GEMINI_API_KEY not set");
-    let client = Gemini::with_model(api_key, Model::Gemini25FlashLite).unwrap();
+/// Shape Gemini is asked to answer in; anything else is treated as an error
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GeminiVerdict {
+    verdict: GeminiMatch,
+    confidence: f32,
+    reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum GeminiMatch {
+    Same,
+    Different,
+}
+
+fn verdict_schema() -> serde_json::Value {
+    serde_json::json!({
+        "type": "OBJECT",
+        "properties": {
+            "verdict": { "type": "STRING", "enum": ["same", "different"] },
+            "confidence": { "type": "NUMBER" },
+            "reason": { "type": "STRING" }
+        },
+        "required": ["verdict", "confidence", "reason"],
+        "propertyOrdering": ["verdict", "confidence", "reason"]
+    })
+}
+
+/// Strictly parse Gemini's JSON answer into a `Verdict`
+fn parse_verdict(text: &str) -> anyhow::Result<Verdict> {
+    let parsed: GeminiVerdict = serde_json::from_str(text.trim()).context("parsing Gemini verdict")?;
+    if !(0.0..=1.0).contains(&parsed.confidence) {
+        anyhow::bail!("Gemini confidence {} is out of range", parsed.confidence);
+    }
+    Ok(Verdict {
+        matched: matches!(parsed.verdict, GeminiMatch::Same),
+        confidence: parsed.confidence,
+        reason: parsed.reason,
+    })
+}
+
+/// Failures talking to Gemini, as seen by callers after retries
+#[derive(Debug)]
+pub enum GeminiError {
+    MissingApiKey,
+    /// The call did not finish within the configured timeout
+    Timeout,
+    /// The request failed or Gemini answered with an error status
+    Upstream(String),
+    /// Gemini answered, but not with a verdict we could parse
+    InvalidResponse(String),
+    /// Too many recent failures; calls are refused until the cool-down passes
+    CircuitOpen,
+}
+
+impl std::fmt::Display for GeminiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GeminiError::MissingApiKey => write!(f, "GEMINI_API_KEY is not set"),
+            GeminiError::Timeout => write!(f, "Gemini did not answer in time"),
+            GeminiError::Upstream(e) => write!(f, "Gemini request failed: {e}"),
+            GeminiError::InvalidResponse(e) => write!(f, "Gemini gave an unusable answer: {e}"),
+            GeminiError::CircuitOpen => write!(f, "Gemini is failing; calls are paused"),
+        }
+    }
+}
+
+impl std::error::Error for GeminiError {}
+
+impl GeminiError {
+    fn is_retryable(&self) -> bool {
+        !matches!(self, GeminiError::MissingApiKey | GeminiError::CircuitOpen)
+    }
+}
+
+impl From<ClientError> for GeminiError {
+    fn from(e: ClientError) -> Self {
+        GeminiError::Upstream(e.to_string())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GeminiConfig {
+    /// Upper bound on a single request
+    pub timeout: Duration,
+    /// Extra attempts after the first one fails
+    pub max_retries: u32,
+    /// Delay before the first retry; doubles on each further retry
+    pub backoff: Duration,
+    /// Consecutive failed calls that open the circuit
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before a trial call is let through
+    pub cool_down: Duration,
+}
+
+impl Default for GeminiConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(15),
+            max_retries: 2,
+            backoff: Duration::from_millis(250),
+            failure_threshold: 5,
+            cool_down: Duration::from_secs(30),
+        }
+    }
+}
 
-    let (mime1, data1) = parse_data_url(image1_b64);
-    let (mime2, data2) = parse_data_url(image2_b64);
+impl GeminiConfig {
+    /// Defaults, overridden by `GEMINI_TIMEOUT_SECS` and `GEMINI_MAX_RETRIES` when set
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Some(secs) = dotenvy::var("GEMINI_TIMEOUT_SECS").ok().and_then(|s| s.parse().ok()) {
+            config.timeout = Duration::from_secs(secs);
+        }
+        if let Some(retries) = dotenvy::var("GEMINI_MAX_RETRIES").ok().and_then(|s| s.parse().ok()) {
+            config.max_retries = retries;
+        }
+        config
+    }
+}
 
-    let response = client.generate_content()
-        .with_system_instruction("Are these two images of the same real-world object or location?           \
-                                 The images may be from very different perspectives or in different lighting.   \
-                                 Be very lenient with what constitutes as the \"same\".                    \
-                                 Answer with only 'yes' or 'no'.".to_string())
-        .with_inline_data(&data1, &mime1)
-        .with_inline_data(&data2, &mime2)
-        .execute().await.unwrap();
+#[derive(Default)]
+struct Breaker {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
 
-    trace!("{}", response.text());
-    Ok(response.text().to_lowercase().contains("yes"))
+/// Verifies guesses by asking Gemini to compare the two images. One instance is
+/// created at startup and shared, so the HTTP client and circuit breaker are too.
+pub struct GeminiVerifier {
+    client: Gemini,
+    config: GeminiConfig,
+    breaker: Mutex<Breaker>,
+}
+
+impl GeminiVerifier {
+    pub fn new(api_key: &str, config: GeminiConfig) -> Result<Self, GeminiError> {
+        let client = Gemini::with_model(api_key, Model::Gemini25FlashLite)?;
+        Ok(Self { client, config, breaker: Mutex::new(Breaker::default()) })
+    }
+
+    pub fn from_env() -> Result<Self, GeminiError> {
+        let api_key = dotenvy::var("GEMINI_API_KEY").map_err(|_| GeminiError::MissingApiKey)?;
+        Self::new(&api_key, GeminiConfig::from_env())
+    }
+
+    fn check_breaker(&self) -> Result<(), GeminiError> {
+        let breaker = self.breaker.lock().unwrap();
+        match breaker.open_until {
+            Some(until) if Instant::now() < until => Err(GeminiError::CircuitOpen),
+            _ => Ok(()),
+        }
+    }
+
+    fn record(&self, succeeded: bool) {
+        let mut breaker = self.breaker.lock().unwrap();
+        if succeeded {
+            *breaker = Breaker::default();
+            return;
+        }
+        breaker.consecutive_failures += 1;
+        if breaker.consecutive_failures >= self.config.failure_threshold {
+            tracing::warn!(
+                "Gemini failed {} times in a row; pausing calls for {:?}",
+                breaker.consecutive_failures,
+                self.config.cool_down
+            );
+            breaker.open_until = Some(Instant::now() + self.config.cool_down);
+        }
+    }
+
+    async fn compare_once(&self, target_b64: &str, submission_b64: &str) -> Result<Verdict, GeminiError> {
+        let (mime1, data1) = parse_data_url(target_b64);
+        let (mime2, data2) = parse_data_url(submission_b64);
+
+        let request = self.client.generate_content()
+            .with_system_instruction("Are these two images of the same real-world object or location?           \
+                                     The images may be from very different perspectives or in different lighting.   \
+                                     Be very lenient with what constitutes as the \"same\".                    \
+                                     Answer with a verdict of 'same' or 'different', your confidence in that    \
+                                     verdict from 0 to 1, and a one-sentence reason a player can read.".to_string())
+            .with_inline_data(&data1, &mime1)
+            .with_inline_data(&data2, &mime2)
+            .with_response_mime_type("application/json")
+            .with_response_schema(verdict_schema())
+            .execute();
+
+        let response = tokio::time::timeout(self.config.timeout, request)
+            .await
+            .map_err(|_| GeminiError::Timeout)??;
+
+        trace!("{}", response.text());
+        parse_verdict(&response.text()).map_err(|e| GeminiError::InvalidResponse(format!("{e:#}")))
+    }
+
+    /// Compare with retries and backoff, failing fast while the circuit is open
+    pub async fn compare_images(&self, target_b64: &str, submission_b64: &str) -> Result<Verdict, GeminiError> {
+        self.check_breaker()?;
+
+        let mut backoff = self.config.backoff;
+        let mut attempt = 0;
+        let result = loop {
+            match self.compare_once(target_b64, submission_b64).await {
+                Ok(verdict) => break Ok(verdict),
+                Err(e) if e.is_retryable() && attempt < self.config.max_retries => {
+                    attempt += 1;
+                    tracing::warn!("Gemini attempt {} failed, retrying in {:?}: {}", attempt, backoff, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => break Err(e),
+            }
+        };
+
+        self.record(result.is_ok());
+        result
+    }
+}
+
+#[async_trait]
+impl ImageVerifier for GeminiVerifier {
+    async fn verify(&self, target_b64: &str, submission_b64: &str) -> anyhow::Result<Verdict> {
+        Ok(self.compare_images(target_b64, submission_b64).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_valid_verdict() {
+        let verdict = parse_verdict(r#" {"verdict": "same", "confidence": 0.9, "reason": "same fountain"} "#).unwrap();
+        assert!(verdict.matched);
+        assert_eq!(verdict.confidence, 0.9);
+        assert_eq!(verdict.reason, "same fountain");
+
+        let verdict = parse_verdict(r#"{"verdict": "different", "confidence": 0.7, "reason": "other statue"}"#).unwrap();
+        assert!(!verdict.matched);
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(parse_verdict(r#"{"verdict": "same", "confidence": 0.9, "reason": "ok", "extra": 1}"#).is_err());
+    }
+
+    #[test]
+    fn rejects_out_of_range_confidence() {
+        assert!(parse_verdict(r#"{"verdict": "same", "confidence": 1.5, "reason": "ok"}"#).is_err());
+        assert!(parse_verdict(r#"{"verdict": "same", "confidence": -0.1, "reason": "ok"}"#).is_err());
+    }
+
+    #[test]
+    fn rejects_plain_text_answers() {
+        assert!(parse_verdict("yes").is_err());
+        assert!(parse_verdict("Yes, these show the same object.").is_err());
+    }
 }
@@ -1,77 +1,331 @@
 #[derive(Deserialize)]
-pub struct NewObject {
-    name: String,
+pub struct NewImage {
+    /// Object to add the image to
+    id: String,
     image: String,
 }
 
-use crate::models::{Player, ServerMessage};
+use crate::ingest::ingest;
+use crate::lobby::{Lobby, now_millis};
+use crate::models::{LobbySettings, LobbyState, MAX_NAME_LEN, MAX_REFERENCE_IMAGES, Player, Playlist};
+use crate::targets;
 use crate::state::AppState;
+use crate::verifier::{CacheStats, DEFAULT_MIN_CONFIDENCE, image_hash, verify_any_within};
 use axum::{
     Json,
-    extract::{State, ws::{WebSocketUpgrade, Message}},
+    extract::{Path, Query, State, ws::{WebSocketUpgrade, Message}},
+    http::StatusCode,
     response::IntoResponse,
 };
 use dotenvy::var;
-use mongodb::bson::doc;
+use mongodb::bson::{doc, oid::ObjectId};
 use serde::Deserialize;
 use std::{collections::HashMap, sync::Arc};
 use futures_util::{StreamExt, SinkExt};
 
 #[derive(Deserialize)]
-pub struct GuessPayload { pub image_b64: String }
+pub struct GuessPayload {
+    pub image_b64: String,
+    /// Regional feed the guess is for; the global feed when missing
+    #[serde(default)]
+    pub region: Option<String>,
+}
 
 #[derive(serde::Serialize)]
-pub struct GuessResponse { pub correct: bool }
+pub struct GuessResponse {
+    pub correct: bool,
+    pub confidence: f32,
+    pub reason: String,
+}
 
+/// Add another reference image to an existing object
 pub async fn add_image_to_gameobject(
     State(state): State<Arc<AppState>>,
-    Json(obj): Json<NewObject>,
-) -> Json<String> {
+    Json(new_image): Json<NewImage>,
+) -> Result<Json<String>, (StatusCode, String)> {
     let db = state
         .mdb
         .database(&var("MONGO_DB_NAME").expect("need MONGO_DB_NAME!"));
     let game_objects = db.collection::<super::models::GameObject>("gameobjects");
 
-    let new_image = super::models::GameObject {
-        id: None,
-        image_b64: obj.image,
-        name: obj.name,
-    };
-    game_objects.insert_one(&new_image).await.unwrap();
+    let id = ObjectId::parse_str(&new_image.id).map_err(|_| (StatusCode::BAD_REQUEST, "id is not a valid id".to_string()))?;
+    let image = ingest(new_image.image).await.map_err(|e| (StatusCode::BAD_REQUEST, e))?.image_b64;
+    // Only push while the last allowed extra slot is still free
+    let full = format!("extra_images.{}", MAX_REFERENCE_IMAGES - 2);
+    let result = game_objects
+        .update_one(doc! { "_id": id, full: { "$exists": false } }, doc! { "$push": { "extra_images": image } })
+        .await
+        .map_err(|e| {
+            tracing::error!("failed to add image to {}: {:?}", id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to add image".to_string())
+        })?;
+    if result.matched_count == 0 {
+        let exists = game_objects
+            .count_documents(doc! { "_id": id })
+            .await
+            .map_err(|e| internal_error(format!("failed to look up object {id}: {e:?}")))?
+            > 0;
+        return Err(if exists {
+            (StatusCode::BAD_REQUEST, format!("an object has at most {MAX_REFERENCE_IMAGES} images"))
+        } else {
+            (StatusCode::NOT_FOUND, "No such object".to_string())
+        });
+    }
 
-    Json("Image registered".to_string())
+    Ok(Json("Image registered".to_string()))
 }
 
 pub async fn register_object(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<super::models::GameObject>,
-) -> Json<String> {
+    Json(mut payload): Json<super::models::GameObject>,
+) -> Result<Json<String>, (StatusCode, String)> {
     let db = state
         .mdb
         .database(&var("MONGO_DB_NAME").expect("need MONGO_DB_NAME!"));
     let game_objects = db.collection::<super::models::GameObject>("gameobjects");
 
-    game_objects.insert_one(payload).await.unwrap();
+    payload.validate().map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let primary = ingest(std::mem::take(&mut payload.image_b64)).await.map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    payload.image_b64 = primary.image_b64;
+    // Fall back to where the photo says it was taken when the device didn't send a position
+    payload.exif_location = primary.gps;
+    if payload.location.is_none() {
+        payload.location = primary.gps;
+        payload.accuracy_m = None;
+    }
+    for extra in &mut payload.extra_images {
+        *extra = ingest(std::mem::take(extra)).await.map_err(|e| (StatusCode::BAD_REQUEST, e))?.image_b64;
+    }
+    payload.id = None;
+    payload.created_at = Some(now_millis());
+    game_objects.insert_one(payload).await.map_err(|e| {
+        tracing::error!("failed to register object: {:?}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Failed to register object".to_string())
+    })?;
 
-    Json("Object registered".to_string())
+    Ok(Json("Object registered".to_string()))
 }
 
 pub async fn submit_guess(
     State(state): State<Arc<AppState>>,
     Json(payload): Json<GuessPayload>,
 ) -> Json<GuessResponse> {
-    if let Some(current) = state.feed.current().await {
-        // Compare using Gemini
-        match crate::gemini::is_same_image(&payload.image_b64, &current.image_b64).await {
-            Ok(correct) => Json(GuessResponse { correct }),
+    let Some(feed) = state.feed_for(payload.region.as_deref()) else {
+        return Json(GuessResponse { correct: false, confidence: 0.0, reason: "Unknown region".to_string() });
+    };
+    if let Some(current) = feed.current().await {
+        let (target_key, submission_hash) = (current.cache_key(), image_hash(&payload.image_b64));
+        let verdict = match state.verdict_cache.get(&target_key, &submission_hash, DEFAULT_MIN_CONFIDENCE) {
+            Some(verdict) => Ok(verdict),
+            None => {
+                let verdict = verify_any_within(
+                    state.guess_deadline,
+                    state.verifier.as_ref(),
+                    current.reference_images(),
+                    &payload.image_b64,
+                    DEFAULT_MIN_CONFIDENCE,
+                )
+                .await;
+                if let Ok(verdict) = &verdict {
+                    state.verdict_cache.insert(target_key, submission_hash, DEFAULT_MIN_CONFIDENCE, verdict.clone());
+                }
+                verdict
+            }
+        };
+        match verdict {
+            Ok(verdict) => Json(GuessResponse {
+                correct: verdict.accepted(DEFAULT_MIN_CONFIDENCE),
+                confidence: verdict.confidence,
+                reason: verdict.reason,
+            }),
             Err(e) => {
-                tracing::error!("gemini compare error: {:?}", e);
-                Json(GuessResponse { correct: false })
+                tracing::error!("image verification error: {:?}", e);
+                Json(GuessResponse { correct: false, confidence: 0.0, reason: "Verification unavailable, try again".to_string() })
             }
         }
     } else {
-        Json(GuessResponse { correct: false })
+        Json(GuessResponse { correct: false, confidence: 0.0, reason: "There is no target right now".to_string() })
+    }
+}
+
+pub async fn verifier_stats(State(state): State<Arc<AppState>>) -> Json<CacheStats> {
+    Json(state.verdict_cache.stats())
+}
+
+pub async fn create_lobby(
+    State(state): State<Arc<AppState>>,
+    settings: Option<Json<LobbySettings>>,
+) -> Result<Json<LobbyState>, (StatusCode, String)> {
+    let db = state
+        .mdb
+        .database(&var("MONGO_DB_NAME").expect("need MONGO_DB_NAME!"));
+
+    let settings = settings.map(|Json(s)| s).unwrap_or_default();
+    settings.validate().map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    if let Some(id) = &settings.playlist_id
+        && targets::find_playlist(&db, id).await.map_err(internal_error)?.is_none()
+    {
+        return Err((StatusCode::BAD_REQUEST, "No such playlist".to_string()));
+    }
+    let lobby_state = LobbyState::new(uuid::Uuid::new_v4().to_string(), settings);
+    if let Err(e) = db.collection::<LobbyState>("lobbies").insert_one(&lobby_state).await {
+        tracing::error!("failed to persist new lobby {}: {:?}", lobby_state.id, e);
+    }
+
+    let lobby = Lobby::new(lobby_state.clone(), db, Arc::clone(&state.verifier), Arc::clone(&state.verdict_cache), state.verify_concurrency, state.guess_deadline);
+    state.lobbies.insert(lobby_state.id.clone(), lobby);
+    tracing::info!("created lobby {}", lobby_state.id);
+
+    Ok(Json(lobby_state))
+}
+
+fn internal_error(e: String) -> (StatusCode, String) {
+    tracing::error!("{}", e);
+    (StatusCode::INTERNAL_SERVER_ERROR, "Database error".to_string())
+}
+
+/// Who is asking, for playlist endpoints. There are no accounts, so this is the
+/// same self-declared name players join with.
+#[derive(Deserialize)]
+pub struct OwnerQuery {
+    owner: Option<String>,
+}
+
+/// Check a playlist from a client and that every object it lists exists
+async fn validate_playlist(db: &mongodb::Database, playlist: &mut Playlist) -> Result<(), (StatusCode, String)> {
+    playlist.validate().map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let missing = targets::missing_objects(db, &playlist.object_ids).await.map_err(internal_error)?;
+    if !missing.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, format!("No such objects: {}", missing.join(", "))));
+    }
+    Ok(())
+}
+
+/// A playlist `owner` may change: 404 when it doesn't exist or is hidden from them,
+/// 403 when they can see it but don't own it
+async fn owned_playlist(db: &mongodb::Database, id: &str, owner: Option<&str>) -> Result<Playlist, (StatusCode, String)> {
+    let playlist = targets::find_playlist(db, id)
+        .await
+        .map_err(internal_error)?
+        .filter(|p| p.visible_to(owner))
+        .ok_or((StatusCode::NOT_FOUND, "No such playlist".to_string()))?;
+    if owner != Some(playlist.owner.as_str()) {
+        return Err((StatusCode::FORBIDDEN, "Only the owner can change this playlist".to_string()));
+    }
+    Ok(playlist)
+}
+
+pub async fn create_playlist(
+    State(state): State<Arc<AppState>>,
+    Json(mut playlist): Json<Playlist>,
+) -> Result<Json<Playlist>, (StatusCode, String)> {
+    let db = state
+        .mdb
+        .database(&var("MONGO_DB_NAME").expect("need MONGO_DB_NAME!"));
+
+    validate_playlist(&db, &mut playlist).await?;
+    playlist.id = Some(ObjectId::new());
+    playlist.created_at = Some(now_millis());
+    db.collection::<Playlist>("playlists")
+        .insert_one(&playlist)
+        .await
+        .map_err(|e| internal_error(format!("failed to create playlist: {e:?}")))?;
+
+    Ok(Json(playlist))
+}
+
+/// Public playlists, plus every playlist of `owner` when given
+pub async fn list_playlists(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<OwnerQuery>,
+) -> Result<Json<Vec<Playlist>>, (StatusCode, String)> {
+    let db = state
+        .mdb
+        .database(&var("MONGO_DB_NAME").expect("need MONGO_DB_NAME!"));
+
+    let filter = match &query.owner {
+        Some(owner) => doc! { "$or": [{ "visibility": "public" }, { "owner": owner }] },
+        None => doc! { "visibility": "public" },
+    };
+    let mut cursor = db
+        .collection::<Playlist>("playlists")
+        .find(filter)
+        .sort(doc! { "name": 1 })
+        .await
+        .map_err(|e| internal_error(format!("failed to list playlists: {e:?}")))?;
+    let mut playlists = Vec::new();
+    while let Some(playlist) = cursor.next().await {
+        playlists.push(playlist.map_err(|e| internal_error(format!("failed to read playlist: {e:?}")))?);
     }
+
+    Ok(Json(playlists))
+}
+
+pub async fn get_playlist(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<OwnerQuery>,
+) -> Result<Json<Playlist>, (StatusCode, String)> {
+    let db = state
+        .mdb
+        .database(&var("MONGO_DB_NAME").expect("need MONGO_DB_NAME!"));
+
+    targets::find_playlist(&db, &id)
+        .await
+        .map_err(internal_error)?
+        .filter(|p| p.visible_to(query.owner.as_deref()))
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, "No such playlist".to_string()))
+}
+
+/// Replace a playlist's contents. Only its owner may, and the owner can't be changed.
+pub async fn update_playlist(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<OwnerQuery>,
+    Json(mut playlist): Json<Playlist>,
+) -> Result<Json<Playlist>, (StatusCode, String)> {
+    let db = state
+        .mdb
+        .database(&var("MONGO_DB_NAME").expect("need MONGO_DB_NAME!"));
+
+    let existing = owned_playlist(&db, &id, query.owner.as_deref()).await?;
+    playlist.owner = existing.owner;
+    validate_playlist(&db, &mut playlist).await?;
+    playlist.id = existing.id;
+    playlist.created_at = existing.created_at;
+    db.collection::<Playlist>("playlists")
+        .replace_one(doc! { "_id": existing.id }, &playlist)
+        .await
+        .map_err(|e| internal_error(format!("failed to update playlist {id}: {e:?}")))?;
+
+    Ok(Json(playlist))
+}
+
+pub async fn delete_playlist(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<OwnerQuery>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let db = state
+        .mdb
+        .database(&var("MONGO_DB_NAME").expect("need MONGO_DB_NAME!"));
+
+    let existing = owned_playlist(&db, &id, query.owner.as_deref()).await?;
+    db.collection::<Playlist>("playlists")
+        .delete_one(doc! { "_id": existing.id })
+        .await
+        .map_err(|e| internal_error(format!("failed to delete playlist {id}: {e:?}")))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_lobby(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<LobbyState>, StatusCode> {
+    let lobby = state.lobbies.get(&id).map(|l| l.clone()).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(lobby.snapshot().await.redacted()))
 }
 
 pub async fn ws_handler(
@@ -79,10 +333,30 @@ pub async fn ws_handler(
     State(state): State<Arc<AppState>>,
     axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
 ) -> impl IntoResponse {
-    let player_name = params.get("player_name").cloned().unwrap_or_default();
-    let _player = Player { name: player_name };
+    // Lobby sockets are handed to the lobby engine; everything else joins the global feed
+    if let Some(lobby_id) = params.get("lobby_id") {
+        let Some(lobby) = state.lobbies.get(lobby_id).map(|l| l.clone()) else {
+            return (StatusCode::NOT_FOUND, "Unknown lobby").into_response();
+        };
+        if params.get("spectate").is_some_and(|v| v == "true") {
+            return ws
+                .on_upgrade(move |socket| async move { lobby.add_spectator(socket).await })
+                .into_response();
+        }
+        let player_name = params.get("player_name").map(|name| name.trim()).unwrap_or_default();
+        if player_name.is_empty() || player_name.len() > MAX_NAME_LEN {
+            return (StatusCode::BAD_REQUEST, format!("player_name must be 1 to {MAX_NAME_LEN} characters")).into_response();
+        }
+        let player = Player { name: player_name.to_string() };
+        let resume_token = params.get("resume_token").cloned();
+        return ws
+            .on_upgrade(move |socket| async move { lobby.add_player(player, socket, resume_token).await })
+            .into_response();
+    }
 
-    let feed = Arc::clone(&state.feed);
+    let Some(feed) = state.feed_for(params.get("region").map(String::as_str)) else {
+        return (StatusCode::NOT_FOUND, "Unknown region").into_response();
+    };
     ws.on_upgrade(move |socket| async move {
         let (mut sender, mut receiver) = socket.split();
 
@@ -97,12 +371,13 @@ pub async fn ws_handler(
         let mut rx = feed.subscribe();
         tokio::spawn(async move {
             while let Ok(msg) = rx.recv().await {
-                if let Ok(json) = serde_json::to_string(&msg) {
-                    if sender.send(Message::Text(json.into())).await.is_err() {
-                        break;
-                    }
+                if let Ok(json) = serde_json::to_string(&msg)
+                    && sender.send(Message::Text(json.into())).await.is_err()
+                {
+                    break;
                 }
             }
         });
     })
+    .into_response()
 }
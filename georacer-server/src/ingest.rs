@@ -0,0 +1,280 @@
+use std::io::Cursor;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use exif::{In, Tag, Value};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
+
+use crate::gemini::parse_data_url;
+use crate::models::GeoPoint;
+
+/// Largest upload accepted, before decoding
+const MAX_UPLOAD_BYTES: usize = 15 * 1024 * 1024;
+/// Request body needed to carry one image of `MAX_UPLOAD_BYTES` as base64, with room
+/// for the data URL prefix and the rest of the JSON
+pub const MAX_IMAGE_BODY_BYTES: usize = MAX_UPLOAD_BYTES / 3 * 4 + 64 * 1024;
+/// Decoding refuses anything wider or taller than this
+const MAX_SOURCE_EDGE: u32 = 12_000;
+/// Smaller photos don't carry enough detail to match guesses against
+const MIN_SOURCE_EDGE: u32 = 64;
+/// Stored images are scaled down to fit this longest edge
+const MAX_STORED_EDGE: u32 = 1600;
+const STORED_JPEG_QUALITY: u8 = 85;
+
+/// A client's image after ingestion
+#[derive(Debug)]
+pub struct IngestedImage {
+    /// Canonical JPEG data URL, without any metadata
+    pub image_b64: String,
+    /// Where the photo's EXIF said it was taken
+    pub gps: Option<GeoPoint>,
+}
+
+/// Decode a client's (possibly data URL) base64 image, check it really is a supported
+/// picture of sensible size, and re-encode it as a downscaled JPEG. Re-encoding drops
+/// all EXIF metadata, so the GPS position is read out first and returned alongside.
+/// HEIC photos are converted too when the server is built with the `heic` feature.
+/// Errors are fit to show the client.
+pub fn ingest_image(image_b64: &str) -> Result<IngestedImage, String> {
+    let (_, data) = parse_data_url(image_b64);
+    let bytes = STANDARD.decode(data.trim()).map_err(|_| "image is not valid base64".to_string())?;
+    if bytes.len() > MAX_UPLOAD_BYTES {
+        return Err(format!("image is larger than {} MB", MAX_UPLOAD_BYTES / (1024 * 1024)));
+    }
+    let gps = read_gps(&bytes);
+    let mut image = if is_heif(&bytes) { decode_heif(&bytes)? } else { decode(&bytes)? };
+
+    if image.width() < MIN_SOURCE_EDGE || image.height() < MIN_SOURCE_EDGE {
+        return Err(format!("image must be at least {MIN_SOURCE_EDGE}x{MIN_SOURCE_EDGE} pixels"));
+    }
+    if image.width() > MAX_STORED_EDGE || image.height() > MAX_STORED_EDGE {
+        image = image.resize(MAX_STORED_EDGE, MAX_STORED_EDGE, FilterType::Lanczos3);
+    }
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, STORED_JPEG_QUALITY)
+        .encode_image(&image.to_rgb8())
+        .map_err(|e| format!("image could not be encoded: {e}"))?;
+    Ok(IngestedImage { image_b64: format!("data:image/jpeg;base64,{}", STANDARD.encode(jpeg)), gps })
+}
+
+/// Decode a JPEG, PNG or WebP upright
+fn decode(bytes: &[u8]) -> Result<DynamicImage, String> {
+    let format = image::guess_format(bytes).map_err(|_| "image format not recognised".to_string())?;
+    if !matches!(format, ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP) {
+        return Err(format!("{format:?} images aren't supported; send JPEG, PNG or WebP"));
+    }
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_SOURCE_EDGE);
+    limits.max_image_height = Some(MAX_SOURCE_EDGE);
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder().map_err(|e| format!("image could not be decoded: {e}"))?;
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut image = DynamicImage::from_decoder(decoder).map_err(|e| format!("image could not be decoded: {e}"))?;
+    // The orientation tag is about to be dropped, so bake the rotation into the pixels
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+/// Decode the primary image of a HEIF container upright
+#[cfg(feature = "heic")]
+fn decode_heif(bytes: &[u8]) -> Result<DynamicImage, String> {
+    use libheif_rs::{ColorSpace, HeifContext, HeifError, LibHeif, RgbChroma};
+
+    let failed = |e: HeifError| format!("HEIC image could not be decoded: {e}");
+    let context = HeifContext::read_from_bytes(bytes).map_err(failed)?;
+    let handle = context.primary_image_handle().map_err(failed)?;
+    if handle.width() > MAX_SOURCE_EDGE || handle.height() > MAX_SOURCE_EDGE {
+        return Err(format!("image must be at most {MAX_SOURCE_EDGE}x{MAX_SOURCE_EDGE} pixels"));
+    }
+    // libheif applies the container's rotation and mirroring while decoding
+    let decoded = LibHeif::new().decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None).map_err(failed)?;
+    let plane = decoded.planes().interleaved.ok_or("HEIC image has no RGB plane".to_string())?;
+
+    // Rows may be padded past the pixels they hold
+    let row_len = plane.width as usize * 3;
+    let pixels = plane.data.chunks(plane.stride).take(plane.height as usize).flat_map(|row| &row[..row_len]).copied().collect();
+    let rgb = image::RgbImage::from_raw(plane.width, plane.height, pixels).ok_or("HEIC image is truncated".to_string())?;
+    Ok(DynamicImage::ImageRgb8(rgb))
+}
+
+#[cfg(not(feature = "heic"))]
+fn decode_heif(_bytes: &[u8]) -> Result<DynamicImage, String> {
+    Err("This server can't read HEIC images; send JPEG, PNG or WebP".to_string())
+}
+
+/// `ingest_image` off the async runtime
+pub async fn ingest(image_b64: String) -> Result<IngestedImage, String> {
+    tokio::task::spawn_blocking(move || ingest_image(&image_b64))
+        .await
+        .map_err(|_| "image processing failed".to_string())?
+}
+
+/// HEIF containers (what iPhones save HEIC photos in) open with an `ftyp` box naming a HEIF brand
+fn is_heif(bytes: &[u8]) -> bool {
+    bytes.len() >= 12
+        && &bytes[4..8] == b"ftyp"
+        && matches!(&bytes[8..12], b"heic" | b"heix" | b"hevc" | b"hevx" | b"heim" | b"heis" | b"mif1" | b"msf1")
+}
+
+/// The GPS position recorded in the image's EXIF, if there is a usable one
+fn read_gps(bytes: &[u8]) -> Option<GeoPoint> {
+    let exif = exif::Reader::new().read_from_container(&mut Cursor::new(bytes)).ok()?;
+    // Degrees, minutes and seconds, negated for the southern or western hemisphere
+    let coordinate = |value_tag: Tag, ref_tag: Tag, negative: u8| -> Option<f64> {
+        let Value::Rational(dms) = &exif.get_field(value_tag, In::PRIMARY)?.value else {
+            return None;
+        };
+        let [degrees, minutes, seconds] = dms.as_slice() else {
+            return None;
+        };
+        let value = degrees.to_f64() + minutes.to_f64() / 60.0 + seconds.to_f64() / 3600.0;
+        let Value::Ascii(reference) = &exif.get_field(ref_tag, In::PRIMARY)?.value else {
+            return None;
+        };
+        let sign = if reference.first()?.first() == Some(&negative) { -1.0 } else { 1.0 };
+        Some(sign * value)
+    };
+    let point = GeoPoint::new(
+        coordinate(Tag::GPSLongitude, Tag::GPSLongitudeRef, b'W')?,
+        coordinate(Tag::GPSLatitude, Tag::GPSLatitudeRef, b'S')?,
+    );
+    // Also weeds out the NaNs a zero denominator produces
+    point.validate().ok()?;
+    Some(point)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(image: &DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image.write_to(&mut Cursor::new(&mut bytes), format).unwrap();
+        bytes
+    }
+
+    fn png(width: u32, height: u32) -> String {
+        STANDARD.encode(encoded(&DynamicImage::new_rgb8(width, height), ImageFormat::Png))
+    }
+
+    fn stored(ingested: &IngestedImage) -> Vec<u8> {
+        let data = ingested.image_b64.strip_prefix("data:image/jpeg;base64,").expect("a JPEG data URL");
+        STANDARD.decode(data).unwrap()
+    }
+
+    /// A 128x128 JPEG carrying an EXIF GPS position, given as whole degrees, minutes
+    /// and seconds with their hemisphere references
+    fn jpeg_with_gps(lat_ref: u8, lat: [u32; 3], lng_ref: u8, lng: [u32; 3]) -> Vec<u8> {
+        // Little-endian TIFF: IFD0 at 8 points at the GPS IFD at 26, whose rationals follow it at 80
+        let mut tiff = b"II\x2a\x00".to_vec();
+        tiff.extend(8u32.to_le_bytes());
+        let entry = |tiff: &mut Vec<u8>, tag: u16, kind: u16, count: u32, value: [u8; 4]| {
+            tiff.extend(tag.to_le_bytes());
+            tiff.extend(kind.to_le_bytes());
+            tiff.extend(count.to_le_bytes());
+            tiff.extend(value);
+        };
+        tiff.extend(1u16.to_le_bytes());
+        entry(&mut tiff, 0x8825, 4, 1, 26u32.to_le_bytes());
+        tiff.extend(0u32.to_le_bytes());
+        tiff.extend(4u16.to_le_bytes());
+        entry(&mut tiff, 1, 2, 2, [lat_ref, 0, 0, 0]);
+        entry(&mut tiff, 2, 5, 3, 80u32.to_le_bytes());
+        entry(&mut tiff, 3, 2, 2, [lng_ref, 0, 0, 0]);
+        entry(&mut tiff, 4, 5, 3, 104u32.to_le_bytes());
+        tiff.extend(0u32.to_le_bytes());
+        for value in lat.into_iter().chain(lng) {
+            tiff.extend(value.to_le_bytes());
+            tiff.extend(1u32.to_le_bytes());
+        }
+
+        let jpeg = encoded(&DynamicImage::new_rgb8(128, 128), ImageFormat::Jpeg);
+        let mut app1 = vec![0xff, 0xe1];
+        app1.extend((2 + 6 + tiff.len() as u16).to_be_bytes());
+        app1.extend(b"Exif\0\0");
+        app1.extend(tiff);
+        // Straight after the start-of-image marker
+        [&jpeg[..2], &app1, &jpeg[2..]].concat()
+    }
+
+    #[test]
+    fn unsupported_formats_are_refused() {
+        let gif = STANDARD.encode(b"GIF89a\x80\x00\x80\x00\x00\x00\x00;");
+        assert!(ingest_image(&gif).unwrap_err().contains("aren't supported"));
+        let text = STANDARD.encode(b"definitely not an image");
+        assert_eq!(ingest_image(&text).unwrap_err(), "image format not recognised");
+        assert_eq!(ingest_image("not base64!").unwrap_err(), "image is not valid base64");
+    }
+
+    #[cfg(not(feature = "heic"))]
+    #[test]
+    fn heic_needs_the_feature() {
+        let heic = STANDARD.encode(b"\0\0\0\x18ftypheic\0\0\0\0mif1heic");
+        assert!(ingest_image(&heic).unwrap_err().contains("can't read HEIC"));
+    }
+
+    #[cfg(feature = "heic")]
+    #[test]
+    fn heic_is_converted_to_jpeg() {
+        use libheif_rs::{Channel, ColorSpace, CompressionFormat, HeifContext, Image, LibHeif, RgbChroma};
+
+        let mut image = Image::new(200, 100, ColorSpace::Rgb(RgbChroma::Rgb)).unwrap();
+        image.create_plane(Channel::Interleaved, 200, 100, 8).unwrap();
+        let lib = LibHeif::new();
+        let mut context = HeifContext::new().unwrap();
+        let mut encoder = lib.encoder_for_format(CompressionFormat::Hevc).unwrap();
+        context.encode_image(&image, &mut encoder, None).unwrap();
+        let heic = context.write_to_bytes().unwrap();
+        assert!(is_heif(&heic));
+
+        let ingested = ingest_image(&STANDARD.encode(heic)).unwrap();
+        let jpeg = image::load_from_memory_with_format(&stored(&ingested), ImageFormat::Jpeg).unwrap();
+        assert_eq!((jpeg.width(), jpeg.height()), (200, 100));
+    }
+
+    #[test]
+    fn tiny_images_are_refused() {
+        assert!(ingest_image(&png(64, 64)).is_ok());
+        assert_eq!(ingest_image(&png(63, 200)).unwrap_err(), "image must be at least 64x64 pixels");
+        assert!(ingest_image(&png(200, 63)).is_err());
+    }
+
+    #[test]
+    fn large_images_are_scaled_to_fit() {
+        let ingested = ingest_image(&format!("data:image/png;base64,{}", png(3200, 800))).unwrap();
+        let jpeg = image::load_from_memory_with_format(&stored(&ingested), ImageFormat::Jpeg).unwrap();
+        assert_eq!((jpeg.width(), jpeg.height()), (1600, 400));
+
+        let ingested = ingest_image(&png(300, 200)).unwrap();
+        let jpeg = image::load_from_memory_with_format(&stored(&ingested), ImageFormat::Jpeg).unwrap();
+        assert_eq!((jpeg.width(), jpeg.height()), (300, 200));
+    }
+
+    #[test]
+    fn gps_is_kept_but_exif_is_dropped() {
+        let photo = jpeg_with_gps(b'S', [33, 51, 30], b'W', [70, 39, 0]);
+        let ingested = ingest_image(&STANDARD.encode(&photo)).unwrap();
+        let gps = ingested.gps.expect("the photo's GPS position");
+        assert!((gps.lat() + 33.858_333).abs() < 1e-6);
+        assert!((gps.lng() + 70.65).abs() < 1e-6);
+
+        let stored = stored(&ingested);
+        assert!(exif::Reader::new().read_from_container(&mut Cursor::new(&stored)).is_err());
+        assert!(read_gps(&stored).is_none());
+    }
+
+    #[test]
+    fn gps_sign_follows_the_hemisphere() {
+        let north_east = read_gps(&jpeg_with_gps(b'N', [48, 51, 0], b'E', [2, 21, 0])).unwrap();
+        assert!((north_east.lat() - 48.85).abs() < 1e-6 && (north_east.lng() - 2.35).abs() < 1e-6);
+        let north_west = read_gps(&jpeg_with_gps(b'N', [40, 45, 0], b'W', [73, 58, 48])).unwrap();
+        assert!((north_west.lat() - 40.75).abs() < 1e-6 && (north_west.lng() + 73.98).abs() < 1e-6);
+        // Out of range positions are discarded rather than stored
+        assert!(read_gps(&jpeg_with_gps(b'N', [91, 0, 0], b'E', [0, 0, 0])).is_none());
+    }
+}
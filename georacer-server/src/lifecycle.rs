@@ -0,0 +1,65 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use mongodb::bson::doc;
+use mongodb::Database;
+
+use crate::lobby::Lobby;
+use crate::models::{LobbyPhase, LobbyState};
+use crate::state::AppState;
+
+/// How often the registry is swept for idle lobbies
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Periodically evict lobbies that have had nobody connected or watching and no activity for `idle_for`.
+/// Evicted lobbies stop their background tasks and lose their persisted document.
+pub fn spawn_idle_reaper(state: Arc<AppState>, idle_for: Duration) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(SWEEP_INTERVAL).await;
+
+            let idle: Vec<String> = state
+                .lobbies
+                .iter()
+                .filter(|entry| entry.value().is_idle(idle_for))
+                .map(|entry| entry.key().clone())
+                .collect();
+
+            for id in idle {
+                // Re-check under the map's lock so a lobby that was just joined survives
+                if let Some((_, lobby)) = state.lobbies.remove_if(&id, |_, lobby| lobby.is_idle(idle_for)) {
+                    lobby.close().await;
+                    tracing::info!("evicted idle lobby {}", id);
+                }
+            }
+        }
+    });
+}
+
+/// Load the lobbies that were persisted before a restart back into the registry and
+/// resume them. Finished games are archived instead of being brought back.
+pub async fn rehydrate_lobbies(state: &AppState, db: &Database) -> anyhow::Result<()> {
+    let mut cursor = db.collection::<LobbyState>("lobbies").find(doc! {}).await?;
+    while let Some(result) = cursor.next().await {
+        let lobby_state = match result {
+            Ok(lobby_state) => lobby_state,
+            Err(e) => {
+                tracing::error!("skipping unreadable lobby document: {:?}", e);
+                continue;
+            }
+        };
+        let id = lobby_state.id.clone();
+        let finished = matches!(lobby_state.phase, LobbyPhase::GameOver { .. });
+        let lobby = Lobby::new(lobby_state, db.clone(), Arc::clone(&state.verifier), Arc::clone(&state.verdict_cache), state.verify_concurrency, state.guess_deadline);
+
+        if finished {
+            lobby.close().await;
+        } else {
+            lobby.resume().await;
+            state.lobbies.insert(id, lobby);
+        }
+    }
+    tracing::info!("rehydrated {} lobbies", state.lobbies.len());
+    Ok(())
+}
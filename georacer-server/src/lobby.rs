@@ -1,178 +1,677 @@
-
-impl LobbyPhase {
-    fn zoom_level(&self) -> Option<f32> {
-        match self {
-            LobbyPhase::Searching { zoom_level, .. } => Some(*zoom_level),
-            _ => None,
-        }
-    }
-}
-
-use mongodb::bson;
 use tracing::trace;
 
-use crate::models::{ClientMessage, GameObject, GameMessage, LobbyPhase, LobbyState, Player, Submission};
+use crate::verifier::{ImageVerifier, Verdict, VerdictCache, image_hash, verify_any_within};
+use crate::targets::{self, HopRange, TargetPool};
+use crate::zoom;
+use crate::models::{ClientMessage, GameObject, GameMessage, LobbyPhase, LobbySettings, LobbyState, Player, RoundEndReason, Submission};
 use axum::extract::ws::{Message, WebSocket};
 use futures_util::sink::SinkExt;
+use futures_util::stream::SplitSink;
 use mongodb::bson::doc;
 use std::collections::HashMap;
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::sync::{broadcast, Mutex};
+use tokio::sync::{broadcast, mpsc, Mutex, MutexGuard, Notify, Semaphore};
+use uuid::Uuid;
+
+/// A player's live socket: a connection id plus a channel for messages meant only for them
+type Connection = (Uuid, mpsc::UnboundedSender<GameMessage>);
+
+/// How long a dropped player keeps their seat while waiting for a reconnect
+const RESUME_GRACE_SECS: u64 = 60;
+
+/// How far before its arrival a guess's client capture time is trusted
+const CAPTURE_TOLERANCE_MS: i64 = 3000;
+
+pub(crate) fn now_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
 
 #[derive(Clone)]
 pub struct Lobby {
     state: Arc<Mutex<LobbyState>>,
     tx: broadcast::Sender<GameMessage>,
     db: mongodb::Database,
+    verifier: Arc<dyn ImageVerifier>,
+    verdicts: Arc<VerdictCache>,
+    /// Bounds how many guesses from this lobby are verified at once; the rest wait their turn
+    verify_permits: Arc<Semaphore>,
+    /// Longest one guess may spend in verification before it counts as unavailable
+    guess_deadline: std::time::Duration,
+    /// Wakes the round loop when a find may have completed the round
+    round_end: Arc<Notify>,
+    /// Set by the host to end the current round without waiting
+    skip_requested: Arc<AtomicBool>,
+    connections: Arc<DashMap<String, Connection>>,
+    /// Live spectator sockets; mirrored into `LobbyState::spectators` for display
+    spectator_sockets: Arc<AtomicUsize>,
+    /// Unix millis of the last join, leave or client message
+    last_activity: Arc<AtomicI64>,
+    /// Set once the lobby has been evicted; background tasks exit when they see it
+    closed: Arc<AtomicBool>,
+    /// Numbers state copies as they are taken for storing, under the lobby lock
+    persist_seq: Arc<AtomicU64>,
+    /// Number of the newest copy written so far; held while writing so writes don't overlap
+    persisted_seq: Arc<Mutex<u64>>,
 }
 
 use futures_util::stream::StreamExt;
 
+/// Finish the game if one of the players who just scored reached the win score.
+/// `awarded` is in ranking order, so the earliest find to cross the line wins.
+fn end_game_if_won(state: &mut LobbyState, awarded: &[String]) -> Option<(Player, Vec<(Player, f32)>)> {
+    let winner = awarded
+        .iter()
+        .find(|name| state.total_scores.get(*name).is_some_and(|total| *total >= state.settings.points_to_win))?;
+    let winner = Player { name: winner.clone() };
+    let leaderboard = state.leaderboard();
+    tracing::info!("{} won lobby {}", winner.name, state.id);
+    state.phase = LobbyPhase::GameOver { leaderboard: leaderboard.clone() };
+    Some((winner, leaderboard))
+}
+
+/// Tell a socket why it can't join, then close it
+async fn refuse(mut ws: WebSocket, message: String) {
+    let json = serde_json::to_string(&GameMessage::Error { message }).unwrap();
+    let _ = ws.send(Message::Text(json.into())).await;
+    let _ = SinkExt::close(&mut ws).await;
+}
+
 impl Lobby {
-    pub fn new(state: LobbyState, db: mongodb::Database) -> Self {
+    pub fn new(
+        state: LobbyState,
+        db: mongodb::Database,
+        verifier: Arc<dyn ImageVerifier>,
+        verdicts: Arc<VerdictCache>,
+        max_concurrent_verifications: usize,
+        guess_deadline: std::time::Duration,
+    ) -> Self {
         let (tx, _) = broadcast::channel(100);
         Self {
             state: Arc::new(Mutex::new(state)),
             tx,
             db,
+            verifier,
+            verdicts,
+            verify_permits: Arc::new(Semaphore::new(max_concurrent_verifications.max(1))),
+            guess_deadline,
+            round_end: Arc::new(Notify::new()),
+            skip_requested: Arc::new(AtomicBool::new(false)),
+            connections: Arc::new(DashMap::new()),
+            spectator_sockets: Arc::new(AtomicUsize::new(0)),
+            last_activity: Arc::new(AtomicI64::new(now_millis())),
+            closed: Arc::new(AtomicBool::new(false)),
+            persist_seq: Arc::new(AtomicU64::new(0)),
+            persisted_seq: Arc::new(Mutex::new(0)),
         }
     }
 
-    pub async fn add_player(&self, player: Player, ws: WebSocket) {
-        // Deduplicate by player name to avoid duplicates from double WS init (e.g., React StrictMode)
+    /// Pick a lobby loaded from the database back up: every seat is held for a
+    /// reconnect and an interrupted countdown or round carries on.
+    pub async fn resume(&self) {
+        let mut state = self.state.lock().await;
+        // Spectator sockets don't survive a restart
+        state.spectators = 0;
+        let now = now_millis();
+        let seated: Vec<String> = state.players.iter().map(|p| p.name.clone()).collect();
+        for name in seated {
+            let disconnected_at = *state.disconnected.entry(name.clone()).or_insert(now);
+            self.expire_seat_after_grace(name, disconnected_at);
+        }
+        let phase = state.phase.clone();
+        tracing::info!("Resuming lobby {} with {} seated players", state.id, state.players.len());
+        drop(state);
+
+        match phase {
+            LobbyPhase::Countdown => self.spawn_round_loop(false),
+            LobbyPhase::Searching { .. } => self.spawn_round_loop(true),
+            _ => {}
+        }
+    }
+
+    /// Send a message to one player's socket only
+    fn send_to(&self, name: &str, msg: GameMessage) {
+        if let Some(conn) = self.connections.get(name) {
+            let _ = conn.1.send(msg);
+        }
+    }
+
+    fn touch(&self) {
+        self.last_activity.store(now_millis(), Ordering::Relaxed);
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// True when nobody is connected or watching and nothing has happened for `idle_for`
+    pub fn is_idle(&self, idle_for: std::time::Duration) -> bool {
+        let idle_ms = now_millis() - self.last_activity.load(Ordering::Relaxed);
+        self.connections.is_empty()
+            && self.spectator_sockets.load(Ordering::SeqCst) == 0
+            && idle_ms >= idle_for.as_millis() as i64
+    }
+
+    /// Stop every background task of this lobby and drop its persisted document.
+    /// Finished games are archived so their results survive; anything else is deleted.
+    pub async fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.round_end.notify_one();
+        let _ = self.tx.send(GameMessage::LobbyClosed);
+
+        let state = self.state.lock().await.clone();
+        let lobbies = self.db.collection::<LobbyState>("lobbies");
+        if let LobbyPhase::GameOver { .. } = state.phase
+            && let Err(e) = self.db.collection::<LobbyState>("lobby_archive").insert_one(&state).await
         {
-            let mut state = self.state.lock().await;
-            state.players.retain(|p| p.name != player.name);
-            state.players.push(player.clone());
+            tracing::error!("Failed to archive lobby {}: {:?}", state.id, e);
+        }
+        if let Err(e) = lobbies.delete_one(doc! { "id": &state.id }).await {
+            tracing::error!("Failed to delete lobby {}: {:?}", state.id, e);
         }
+    }
+
+    /// Seat a player and start serving their socket. A valid `resume_token` reattaches
+    /// the socket to the identity it was issued for, keeping its seat and scores. Without
+    /// one, names that are seated or awaiting a reconnect are refused.
+    pub async fn add_player(&self, player: Player, ws: WebSocket, resume_token: Option<String>) {
+        // Messages meant only for this socket, such as rejected requests
+        let (direct_tx, direct_rx) = mpsc::unbounded_channel::<GameMessage>();
+        let conn_id = Uuid::new_v4();
+        self.touch();
+
+        let (player, resume_token) = {
+            let mut state = self.state.lock().await;
+            if state.kicked.contains(&player.name) {
+                tracing::info!("Refused kicked player {} in lobby {}", player.name, state.id);
+                drop(state);
+                refuse(ws, "You were removed from this lobby".to_string()).await;
+                return;
+            }
+            let resumed = resume_token.and_then(|token| state.resume_tokens.get(&token).cloned().map(|name| (token, name)));
+            let seated = match resumed {
+                Some((token, name)) if state.players.iter().any(|p| p.name == name) => {
+                    tracing::info!("{} resumed their seat in lobby {}", name, state.id);
+                    state.disconnected.remove(&name);
+                    Some((Player { name }, token))
+                }
+                _ => None,
+            };
+            // Without a valid token, a seat that is live or held for a reconnect stays with its owner
+            let taken = self.connections.contains_key(&player.name) || state.disconnected.contains_key(&player.name);
+            if seated.is_none() && taken {
+                tracing::info!("Refused {} in lobby {}: name already seated", player.name, state.id);
+                drop(state);
+                refuse(ws, "That name is already taken in this lobby".to_string()).await;
+                return;
+            }
+            let (player, token) = seated.unwrap_or_else(|| {
+                state.players.retain(|p| p.name != player.name);
+                state.players.push(player.clone());
+                state.disconnected.remove(&player.name);
+                let token = Uuid::new_v4().to_string();
+                state.resume_tokens.retain(|_, name| *name != player.name);
+                state.resume_tokens.insert(token.clone(), player.name.clone());
+                (player, token)
+            });
+            if state.host.is_none() {
+                state.host = Some(player.name.clone());
+            }
+            self.connections.insert(player.name.clone(), (conn_id, direct_tx.clone()));
+            self.persist(state).await;
+            (player, token)
+        };
+        let _ = direct_tx.send(GameMessage::Joined { player: player.clone(), resume_token });
 
-        let (mut sender, mut receiver) = ws.split();
+        let (sender, mut receiver) = ws.split();
         let cself = self.clone();
         let player_for_cleanup = player.clone();
 
         tokio::spawn(async move {
             while let Some(Ok(msg)) = receiver.next().await {
                 //trace!("Received WS message: {:?}", &msg.clone().into_text().unwrap());
-                if let Message::Text(text) = msg {
-                    if let Ok(client_msg) = serde_json::from_str::<ClientMessage>(&text) {
-                        //trace!("Parsed message: {:?}", &format!("{client_msg:?}")[..40]);
-                        match client_msg {
-                            ClientMessage::StartGame => {
-                                cself.start_game().await;
-                            }
-                            ClientMessage::SubmitGuess { image_b64 } => {
-                                let submission = Submission {
-                                    player: player.clone(),
-                                    image_b64,
-                                };
-                                cself.submit_guess(submission).await;
-                            }
-                        }
+                let Message::Text(text) = msg else {
+                    continue;
+                };
+                let result = match serde_json::from_str::<ClientMessage>(&text) {
+                    //trace!("Parsed message: {:?}", &format!("{client_msg:?}")[..40]);
+                    Ok(client_msg) => cself.handle_client_message(&player, conn_id, client_msg).await,
+                    // Tell the sender why, e.g. settings with a field of the wrong type
+                    Err(e) => Err(format!("Invalid message: {e}")),
+                };
+                if let Err(message) = result {
+                    let _ = direct_tx.send(GameMessage::Error { message });
+                }
+            }
+            // Receiver ended => WS closed. Hold the seat for a while unless a newer socket replaced it.
+            if cself
+                .connections
+                .remove_if(&player_for_cleanup.name, |_, (id, _)| *id == conn_id)
+                .is_some()
+            {
+                cself.touch();
+                let disconnected_at = now_millis();
+                {
+                    let mut state = cself.state.lock().await;
+                    state.disconnected.insert(player_for_cleanup.name.clone(), disconnected_at);
+                    if state.host.as_deref() == Some(player_for_cleanup.name.as_str()) {
+                        // Hand the lobby to whoever has been connected the longest
+                        state.host = state.next_host();
+                        tracing::info!("Host of lobby {} is now {:?}", state.id, state.host);
                     }
                 }
+                cself.expire_seat_after_grace(player_for_cleanup.name.clone(), disconnected_at);
+            }
+            cself.broadcast_state().await;
+        });
+
+        self.spawn_socket_writer(sender, direct_rx);
+
+        self.broadcast_state().await;
+    }
+
+    /// Attach a read-only socket that sees every broadcast but holds no seat, so it
+    /// never affects scoring or round progress (e.g. a projector at an event).
+    pub async fn add_spectator(&self, ws: WebSocket) {
+        let (direct_tx, direct_rx) = mpsc::unbounded_channel::<GameMessage>();
+        self.touch();
+        {
+            let mut state = self.state.lock().await;
+            state.spectators = self.spectator_sockets.fetch_add(1, Ordering::SeqCst) + 1;
+            tracing::info!("Spectator joined lobby {} ({} watching)", state.id, state.spectators);
+        }
+
+        let (sender, mut receiver) = ws.split();
+        let cself = self.clone();
+        tokio::spawn(async move {
+            while let Some(Ok(msg)) = receiver.next().await {
+                if let Message::Text(text) = msg
+                    && let Ok(client_msg) = serde_json::from_str::<ClientMessage>(&text)
+                    && !matches!(client_msg, ClientMessage::Ping)
+                {
+                    let _ = direct_tx.send(GameMessage::Error { message: "Spectators can only watch".to_string() });
+                }
             }
-            // Receiver ended => WS closed. Remove the player and broadcast.
+            cself.touch();
             {
                 let mut state = cself.state.lock().await;
-                state.players.retain(|p| p.name != player_for_cleanup.name);
+                state.spectators = cself.spectator_sockets.fetch_sub(1, Ordering::SeqCst) - 1;
             }
             cself.broadcast_state().await;
         });
 
-        // Broadcast game state updates
+        self.spawn_socket_writer(sender, direct_rx);
+        self.broadcast_state().await;
+    }
+
+    /// Forward lobby broadcasts and socket-specific messages to a client
+    fn spawn_socket_writer(
+        &self,
+        mut sender: SplitSink<WebSocket, Message>,
+        mut direct_rx: mpsc::UnboundedReceiver<GameMessage>,
+    ) {
         let mut rx = self.tx.subscribe();
         tokio::spawn(async move {
-            while let Ok(msg) = rx.recv().await {
-                dbg!(&msg);
+            loop {
+                let msg = tokio::select! {
+                    msg = rx.recv() => match msg {
+                        Ok(msg) => msg,
+                        // A slow socket missed some messages; the next GameState catches it up
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    Some(msg) = direct_rx.recv() => msg,
+                };
                 let json = serde_json::to_string(&msg).unwrap();
                 if sender.send(Message::Text(json.into())).await.is_err() {
                     break;
                 }
+                if let GameMessage::Kicked | GameMessage::LobbyClosed = msg {
+                    let _ = sender.close().await;
+                    break;
+                }
             }
         });
+    }
+
+    /// Free a dropped player's seat if they have not come back when the grace period ends
+    fn expire_seat_after_grace(&self, name: String, disconnected_at: i64) {
+        let slf = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_secs(RESUME_GRACE_SECS)).await;
+            {
+                let mut state = slf.state.lock().await;
+                if state.disconnected.get(&name) != Some(&disconnected_at) {
+                    // They reconnected (and possibly dropped again since)
+                    return;
+                }
+                state.disconnected.remove(&name);
+                state.players.retain(|p| p.name != name);
+                state.resume_tokens.retain(|_, n| *n != name);
+                if state.host.is_none() {
+                    state.host = state.next_host();
+                }
+                tracing::info!("{} did not return to lobby {}; seat released", name, state.id);
+            }
+            slf.broadcast_state().await;
+        });
+    }
+
+    async fn handle_client_message(&self, player: &Player, conn_id: Uuid, msg: ClientMessage) -> Result<(), String> {
+        // A kicked or replaced socket no longer speaks for the seat
+        if self.connections.get(&player.name).is_none_or(|conn| conn.0 != conn_id) {
+            return Err("You no longer hold a seat in this lobby".to_string());
+        }
+        self.touch();
+        match msg {
+            ClientMessage::Ping => {}
+            ClientMessage::StartGame => {
+                self.ensure_host(player).await?;
+                self.start_game().await?;
+            }
+            ClientMessage::SubmitGuess { image_b64, captured_at } => {
+                self.submit_guess(player, image_b64, captured_at).await?;
+            }
+            ClientMessage::UpdateSettings { settings } => {
+                self.ensure_host(player).await?;
+                self.update_settings(settings).await?;
+            }
+            ClientMessage::KickPlayer { name } => {
+                self.ensure_host(player).await?;
+                self.kick_player(&name).await?;
+            }
+            ClientMessage::SkipTarget => {
+                self.ensure_host(player).await?;
+                self.skip_target().await?;
+            }
+            ClientMessage::ReportLocation { location } => {
+                location.validate()?;
+                self.state.lock().await.locations.insert(player.name.clone(), location);
+            }
+        }
+        Ok(())
+    }
+
+    async fn ensure_host(&self, player: &Player) -> Result<(), String> {
+        if self.state.lock().await.host.as_deref() == Some(player.name.as_str()) {
+            Ok(())
+        } else {
+            Err("Only the host can do that".to_string())
+        }
+    }
+
+    /// Remove a player from the lobby and close their socket. They can't rejoin under
+    /// the same name for the rest of the lobby's life.
+    pub async fn kick_player(&self, name: &str) -> Result<(), String> {
+        let mut state = self.state.lock().await;
+        if state.host.as_deref() == Some(name) {
+            return Err("The host cannot kick themselves".to_string());
+        }
+        if !state.players.iter().any(|p| p.name == name) {
+            return Err(format!("No player named {name}"));
+        }
+        state.players.retain(|p| p.name != name);
+        state.disconnected.remove(name);
+        state.resume_tokens.retain(|_, n| n != name);
+        state.kicked.push(name.to_string());
+        if let Some((_, (_, tx))) = self.connections.remove(name) {
+            let _ = tx.send(GameMessage::Kicked);
+        }
+        tracing::info!("Kicked {} from lobby {}", name, state.id);
+        self.persist(state).await;
 
         self.broadcast_state().await;
+        Ok(())
+    }
+
+    /// End the current round now and move on to the next target
+    pub async fn skip_target(&self) -> Result<(), String> {
+        if !matches!(self.state.lock().await.phase, LobbyPhase::Searching { .. }) {
+            return Err("There is no target to skip".to_string());
+        }
+        self.skip_requested.store(true, Ordering::SeqCst);
+        self.round_end.notify_one();
+        Ok(())
     }
 
     async fn broadcast_state(&self) {
-        let state = self.state.lock().await.clone();
+        let state = self.state.lock().await.redacted();
         // Best-effort broadcast; do not panic if there are no subscribers
-        let _ = self.tx.send(GameMessage::GameState(state));
+        let _ = self.tx.send(GameMessage::GameState(Box::new(state)));
+    }
+
+    pub async fn snapshot(&self) -> LobbyState {
+        self.state.lock().await.clone()
+    }
+
+    /// Release the lobby lock, then store the state as it was. Copies are numbered while
+    /// the lock is held, so a slow write of an older copy can't overwrite a newer one.
+    async fn persist(&self, state: MutexGuard<'_, LobbyState>) {
+        let seq = self.persist_seq.fetch_add(1, Ordering::SeqCst) + 1;
+        let snapshot = state.clone();
+        drop(state);
+
+        let mut persisted = self.persisted_seq.lock().await;
+        if *persisted > seq {
+            return;
+        }
+        let lobbies = self.db.collection::<LobbyState>("lobbies");
+        if let Err(e) = lobbies.replace_one(doc! { "id": &snapshot.id }, &snapshot).await {
+            tracing::error!("Failed to persist lobby {}: {:?}", snapshot.id, e);
+        }
+        *persisted = seq;
+    }
+
+    /// Replace the lobby settings. Only allowed before the game has started.
+    pub async fn update_settings(&self, settings: LobbySettings) -> Result<(), String> {
+        settings.validate()?;
+        if let Some(id) = &settings.playlist_id
+            && targets::find_playlist(&self.db, id).await?.is_none()
+        {
+            return Err("No such playlist".to_string());
+        }
+
+        let mut state = self.state.lock().await;
+        if !matches!(state.phase, LobbyPhase::WaitingForStart) {
+            return Err("Settings can only be changed before the game starts".to_string());
+        }
+        state.settings = settings;
+        self.persist(state).await;
+
+        self.broadcast_state().await;
+        Ok(())
     }
 
-    /// Spawn the continuous round loop with fixed duration per round
-    pub fn spawn_round_loop(self: &Arc<Self>, round_secs: u64) {
-        let slf = Arc::clone(self);
+    /// Spawn the continuous round loop. A round ends when its time runs out, when
+    /// `scorers_per_target` players have found the target, or when every active
+    /// player has, whichever comes first. With `resume_current` the loop picks up
+    /// the round already in the state (e.g. after a restart) instead of starting a new one.
+    /// No new round starts while every seated player is disconnected.
+    pub fn spawn_round_loop(&self, resume_current: bool) {
+        let slf = self.clone();
         tokio::spawn(async move {
+            let mut resume_current = resume_current;
             loop {
-                // Pick a random target
-                let game_objects = slf.db.collection::<GameObject>("gameobjects");
-                let pipeline = vec![doc! { "$sample": { "size": 1 } }];
-                let mut cursor = game_objects.aggregate(pipeline).await.unwrap();
-
-                if let Some(result) = cursor.next().await {
-                    if let Ok(doc) = result {
-                        match bson::from_document::<GameObject>(doc) {
-                            Ok(target) => {
-                                // Start new round
-                                {
-                                    let mut state = slf.state.lock().await;
-                                    state.phase = LobbyPhase::Searching {
-                                        target: target.clone(),
-                                        scores: HashMap::new(),
-                                        zoom_level: 1.0,
-                                    };
-                                }
-                                let _ = slf.tx.send(GameMessage::NewRound { target: target.clone() });
-                                slf.broadcast_state().await;
-
-                                // Tick every second
-                                let mut seconds_left = round_secs;
-                                while seconds_left > 0 {
-                                    {
-                                        let state = slf.state.lock().await;
-                                        let (submitted, active) = match &state.phase {
-                                            LobbyPhase::Searching { scores, .. } => (scores.len(), state.players.len()),
-                                            _ => (0, state.players.len()),
-                                        };
-                                        let _ = slf.tx.send(GameMessage::Tick { seconds_left: seconds_left as u8, submitted, active });
-                                    }
-                                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                                    seconds_left -= 1;
-                                }
-
-                                // End of round: emit RoundOver with the scores
-                                let scores_snapshot = {
-                                    let state = slf.state.lock().await;
-                                    match &state.phase {
-                                        LobbyPhase::Searching { scores, .. } => scores.clone(),
-                                        _ => HashMap::new(),
-                                    }
-                                };
-                                let _ = slf.tx.send(GameMessage::RoundOver { scores: scores_snapshot });
-
-                                // Continue immediately to next round
-                                continue;
-                            }
-                            Err(e) => {
-                                tracing::error!("Failed to deserialize target object: {:?}", e);
-                            }
+                if slf.is_closed() {
+                    return;
+                }
+                {
+                    let mut state = slf.state.lock().await;
+                    if state.players.is_empty() {
+                        // Everyone left; stop picking targets until someone starts a new game
+                        tracing::info!("Lobby {} is empty; stopping rounds", state.id);
+                        state.phase = LobbyPhase::WaitingForStart;
+                        slf.persist(state).await;
+                        return;
+                    }
+                    if !resume_current && state.connected_players().next().is_none() {
+                        // Seats are only being held for reconnects; wait for someone before the next target
+                        drop(state);
+                        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                        continue;
+                    }
+                }
+                slf.skip_requested.store(false, Ordering::SeqCst);
+                if !std::mem::take(&mut resume_current) {
+                    slf.start_new_round().await;
+                }
+                let deadline_ms = match slf.state.lock().await.phase {
+                    LobbyPhase::Searching { deadline_ms, .. } => deadline_ms,
+                    // Target selection failed or the pool ran out
+                    _ => break,
+                };
+                slf.spawn_zoom_task();
+
+                // Tick every second, waking early whenever someone finds the target
+                let remaining_ms = (deadline_ms - now_millis()).max(0) as u64;
+                let deadline = tokio::time::Instant::now() + std::time::Duration::from_millis(remaining_ms);
+                let mut next_tick = tokio::time::Instant::now();
+                let reason = loop {
+                    if slf.is_closed() {
+                        return;
+                    }
+                    {
+                        let state = slf.state.lock().await;
+                        let LobbyPhase::Searching { scores, .. } = &state.phase else {
+                            // The game ended mid-round; no further rounds are played
+                            return;
+                        };
+                        if let Some(reason) = state.round_end_reason() {
+                            break reason;
+                        }
+                        if slf.skip_requested.swap(false, Ordering::SeqCst) {
+                            break RoundEndReason::Skipped;
+                        }
+                        let now = tokio::time::Instant::now();
+                        if now >= deadline {
+                            break RoundEndReason::TimeUp;
                         }
+                        if now >= next_tick {
+                            let (submitted, active) = (scores.len(), state.connected_players().count());
+                            let seconds_left = (deadline - now).as_secs_f32().ceil() as u32;
+                            let _ = slf.tx.send(GameMessage::Tick { seconds_left, submitted, active });
+                            next_tick += std::time::Duration::from_secs(1);
+                        }
+                    }
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(next_tick.min(deadline)) => {}
+                        _ = slf.round_end.notified() => {}
+                    }
+                };
+
+                // End of round: score finds still waiting on slower verifications, then
+                // emit RoundOver with the scores and reveal the target
+                let (scores_snapshot, target) = {
+                    let mut state = slf.state.lock().await;
+                    let awarded = state.settle_finds(true);
+                    if let Some(winner) = end_game_if_won(&mut state, &awarded) {
+                        slf.persist(state).await;
+                        slf.announce_winner(winner).await;
+                        return;
+                    }
+                    match &state.phase {
+                        LobbyPhase::Searching { scores, target, .. } => (scores.clone(), target.for_display()),
+                        _ => return,
+                    }
+                };
+                tracing::info!("Round over ({:?})", reason);
+                let _ = slf.tx.send(GameMessage::RoundOver { scores: scores_snapshot, reason, target });
+
+                // Continue immediately to next round
+            }
+        });
+    }
+
+    /// Periodically zoom out of the current target until the round changes,
+    /// rendering each step's view on the server
+    fn spawn_zoom_task(&self) {
+        let slf = self.clone();
+        tokio::spawn(async move {
+            let (target, settings, mut nzoom_level, has_view) = {
+                let state = slf.state.lock().await;
+                match &state.phase {
+                    LobbyPhase::Searching { target, zoom_level, view_b64, .. } => {
+                        (target.clone(), state.settings.clone(), *zoom_level, !view_b64.is_empty())
+                    }
+                    _ => return,
+                }
+            };
+            let image_b64 = target.image_b64.clone();
+            let image = match tokio::task::spawn_blocking(move || zoom::decode_image(&image_b64)).await {
+                Ok(Ok(image)) => Arc::new(image),
+                Ok(Err(e)) => {
+                    tracing::error!("Failed to decode target {:?} for zooming: {:?}", target.name, e);
+                    return;
+                }
+                Err(e) => {
+                    tracing::error!("Zoom decode task failed: {:?}", e);
+                    return;
+                }
+            };
+
+            // Views are not persisted, so a round resumed after a restart has none yet
+            if !has_view {
+                let frame = Arc::clone(&image);
+                if let Ok(Ok(view)) = tokio::task::spawn_blocking(move || zoom::render_view(&frame, nzoom_level)).await {
+                    let mut state = slf.state.lock().await;
+                    if let LobbyPhase::Searching { target: ref current, ref mut view_b64, .. } = state.phase
+                        && current.id == target.id
+                    {
+                        *view_b64 = view;
                     }
                 }
+            }
 
-                // If no object found, wait briefly before retrying
-                tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+            loop {
+                tokio::time::sleep(std::time::Duration::from_secs(settings.zoom_interval_secs)).await;
+                nzoom_level -= settings.zoom_step;
+
+                if nzoom_level < settings.zoom_floor || slf.is_closed() {
+                    break;
+                }
+
+                let frame = Arc::clone(&image);
+                let view = match tokio::task::spawn_blocking(move || zoom::render_view(&frame, nzoom_level)).await {
+                    Ok(Ok(view)) => view,
+                    Ok(Err(e)) => {
+                        tracing::error!("Failed to render zoom step: {:?}", e);
+                        break;
+                    }
+                    Err(e) => {
+                        tracing::error!("Zoom render task failed: {:?}", e);
+                        break;
+                    }
+                };
+
+                let mut state = slf.state.lock().await;
+                match state.phase {
+                    LobbyPhase::Searching { target: ref current, ref mut zoom_level, ref mut view_b64, .. }
+                        if current.id == target.id =>
+                    {
+                        *zoom_level = nzoom_level;
+                        *view_b64 = view.clone();
+                        let _ = slf.tx.send(GameMessage::UpdateImage { zoom_level: nzoom_level, image_b64: view });
+                    }
+                    _ => break,
+                }
+                slf.persist(state).await;
             }
         });
     }
 
-    pub async fn start_game(&self) {
+    pub async fn start_game(&self) -> Result<(), String> {
         let mut state = self.state.lock().await;
-        if let LobbyPhase::WaitingForStart = state.phase {
+        // A finished game can be restarted from the leaderboard with fresh scores
+        if let LobbyPhase::WaitingForStart | LobbyPhase::GameOver { .. } = state.phase {
+            state.check_search_area()?;
             state.phase = LobbyPhase::Countdown;
-            
-            let lobby_id = state.id.to_string();
+            state.total_scores.clear();
+            state.used_targets.clear();
+            state.last_target_location = None;
             drop(state);
 
             // Emit countdown (best-effort)
@@ -183,173 +682,223 @@ impl Lobby {
             tokio::spawn(async move {
                 tokio::time::sleep(std::time::Duration::from_secs(3)).await;
 
-                let mut state = self_clone.state.lock().await;
-                if let LobbyPhase::Countdown = state.phase {
-                    let game_objects = self_clone.db.collection::<GameObject>("gameobjects");
-                    let pipeline = vec![doc! { "$sample": { "size": 1 } }];
-                    let mut cursor = game_objects.aggregate(pipeline).await.unwrap();
-
-                    if let Some(result) = cursor.next().await {
-                        match result {
-                            Ok(doc) => {
-                                match bson::from_document::<GameObject>(doc) {
-                                    Ok(target) => {
-                                        trace!("Found target object: {:?}", target.name);
-                                        state.phase = LobbyPhase::Searching {
-                                            target: target.clone(),
-                                            scores: HashMap::new(),
-                                            zoom_level: 1.0,
-                                        };
-
-                                        tracing::info!("Emitting NewRound (initial) for lobby {}", state.id);
-                                        let _ = self_clone.tx.send(GameMessage::NewRound { target: target.clone() });
-
-                                        // Start periodic zoom update task for this round
-                                        let zoom_task_self_clone = self_clone.clone();
-                                        tokio::spawn(async move {
-                                            let mut nzoom_level = 1.0;
-                                            loop {
-                                                tokio::time::sleep(std::time::Duration::from_secs(3)).await;
-                                                nzoom_level -= 0.1;
-
-                                                if nzoom_level < 0.1 {
-                                                    break;
-                                                }
-
-                                                let mut state = zoom_task_self_clone.state.lock().await;
-                                                if let LobbyPhase::Searching { ref mut zoom_level, .. } = state.phase {
-                                                    *zoom_level = nzoom_level;
-                                                    let _ = zoom_task_self_clone.tx.send(GameMessage::UpdateImage { zoom_level: *zoom_level });
-
-                                                    let lobbies = zoom_task_self_clone.db.collection::<LobbyState>("lobbies");
-                                                    lobbies
-                                                        .replace_one(doc! { "id": &state.id.to_string() }, state.clone())
-                                                        .await
-                                                        .unwrap();
-                                                } else {
-                                                    break;
-                                                }
-                                            }
-                                        });
-                                    }
-                                    Err(e) => {
-                                        tracing::error!("Failed to deserialize target object from bson: {:?}", e);
-                                        state.phase = LobbyPhase::WaitingForStart;
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                tracing::error!("Mongo cursor error during initial target fetch: {:?}", e);
-                                state.phase = LobbyPhase::WaitingForStart;
-                            }
-                        }
-                    } else {
-                        trace!("No game objects found in database.");
-                        state.phase = LobbyPhase::WaitingForStart;
-                    }
-
-                    // Persist updated lobby state and broadcast
-                    let lobbies = self_clone.db.collection::<LobbyState>("lobbies");
-                    lobbies
-                        .replace_one(doc! { "id": &lobby_id }, state.clone())
-                        .await
-                        .unwrap();
-
-                    drop(state);
-
-                    self_clone.broadcast_state().await;
+                if let LobbyPhase::Countdown = self_clone.state.lock().await.phase {
+                    self_clone.spawn_round_loop(false);
                 }
             });
         }
+        Ok(())
     }
 
-    pub async fn submit_guess(&self, submission: Submission) {
-        let mut state = self.state.lock().await;
-        if let LobbyPhase::Searching { target, mut scores, .. } = state.clone().phase {
-            if !scores.contains_key(&submission.player.name) {
-                let correct = crate::gemini::is_same_image(&target.image_b64, &submission.image_b64)
-                    .await
-                    .unwrap();
-
-                let _ = self.tx
-                    .send(GameMessage::GuessResult { correct });
-
-                if correct {
-                    // Points equal to active players who have not yet submitted
-                    let score = state.players.len() - scores.len() - 1;
-                    scores.insert(submission.player.name.clone(), score as f32);
-
-                    let total_score = state.total_scores.entry(submission.player.name.clone()).or_insert(0.0);
-                    *total_score += score as f32;
-
-                    // Stay in Searching phase until round timer ends
-                    state.phase = LobbyPhase::Searching {
-                        target,
-                        scores,
-                        zoom_level: state.phase.zoom_level().unwrap_or(1.0),
-                    };
-
-                    // Broadcast updated state for leaderboard
-                    self.broadcast_state().await;
+    /// Stamp a guess and queue it for verification. The lobby lock is only held to
+    /// record the guess; verification runs in the background and its result is
+    /// applied by `apply_verdict`.
+    pub async fn submit_guess(&self, player: &Player, image_b64: String, captured_at: Option<i64>) -> Result<(), String> {
+        let submission_hash = image_hash(&image_b64);
+        let (references, target_key, cached, submission, min_confidence) = {
+            let mut state = self.state.lock().await;
+            let round = state.round;
+            let min_confidence = state.settings.min_confidence;
+            if !state.players.iter().any(|p| p.name == player.name) {
+                return Err("You don't hold a seat in this lobby".to_string());
+            }
+            let LobbyPhase::Searching { target, scores, pending, confirmed, .. } = &mut state.phase else {
+                return Err("There is nothing to guess right now".to_string());
+            };
+            if scores.contains_key(&player.name) || confirmed.iter().any(|(name, _)| *name == player.name) {
+                return Err("You already found this one".to_string());
+            }
+            if pending.contains_key(&player.name) {
+                return Err("Your last guess is still being checked".to_string());
+            }
+            let target_key = target.cache_key();
+            let cached = self.verdicts.get(&target_key, &submission_hash, min_confidence);
+            if let Some(verdict) = &cached
+                && !verdict.accepted(min_confidence)
+            {
+                return Err("That exact photo was already checked and didn't match".to_string());
+            }
+            let submission = Submission { player: player.clone(), image_b64, round, received_at: now_millis(), captured_at };
+            pending.insert(player.name.clone(), submission.timestamp(CAPTURE_TOLERANCE_MS));
+            let references: Vec<String> = target.reference_images().map(str::to_string).collect();
+            (references, target_key, cached, submission, min_confidence)
+        };
+
+        let slf = self.clone();
+        tokio::spawn(async move {
+            let verdict = match cached {
+                Some(verdict) => Ok(verdict),
+                None => {
+                    let _permit = slf.verify_permits.acquire().await;
+                    let verdict = verify_any_within(
+                        slf.guess_deadline,
+                        slf.verifier.as_ref(),
+                        references.iter().map(String::as_str),
+                        &submission.image_b64,
+                        min_confidence,
+                    )
+                    .await;
+                    if let Ok(verdict) = &verdict {
+                        slf.verdicts.insert(target_key, submission_hash, min_confidence, verdict.clone());
+                    }
+                    verdict
                 }
+            };
+            slf.apply_verdict(submission, verdict, min_confidence).await;
+        });
+        Ok(())
+    }
+
+    /// Record the outcome of a verified guess in a short critical section
+    async fn apply_verdict(&self, submission: Submission, verdict: anyhow::Result<Verdict>, min_confidence: f32) {
+        let mut state = self.state.lock().await;
+        let current_round = state.round == submission.round;
+        if current_round && let LobbyPhase::Searching { pending, .. } = &mut state.phase {
+            pending.remove(&submission.player.name);
+        }
+
+        let result = match verdict {
+            Ok(verdict) => GameMessage::GuessResult {
+                correct: verdict.accepted(min_confidence),
+                confidence: verdict.confidence,
+                reason: verdict.reason,
+            },
+            Err(e) => {
+                tracing::error!("image verification error: {:?}", e);
+                GameMessage::VerificationUnavailable { reason: "Verification unavailable, try again".to_string() }
             }
+        };
+        let correct = matches!(result, GameMessage::GuessResult { correct: true, .. });
+        if !current_round || !matches!(state.phase, LobbyPhase::Searching { .. }) {
+            // The round ended while this guess was being checked
+            self.send_to(
+                &submission.player.name,
+                GameMessage::Error { message: "That round is already over".to_string() },
+            );
+            return;
+        }
+        self.send_to(&submission.player.name, result);
+
+        if correct && let LobbyPhase::Searching { confirmed, .. } = &mut state.phase {
+            confirmed.push((submission.player.name.clone(), submission.timestamp(CAPTURE_TOLERANCE_MS)));
         }
+        // Even a rejection can unblock later finds that were waiting on this guess
+        let awarded = state.settle_finds(false);
+        if awarded.is_empty() {
+            return;
+        }
+        if let Some(winner) = end_game_if_won(&mut state, &awarded) {
+            self.persist(state).await;
+            self.announce_winner(winner).await;
+            return;
+        }
+        drop(state);
+        self.round_end.notify_one();
+
+        // Broadcast updated state for leaderboard
+        self.broadcast_state().await;
+    }
+
+    async fn announce_winner(&self, (winner, leaderboard): (Player, Vec<(Player, f32)>)) {
+        let _ = self.tx.send(GameMessage::GameOver { winner, leaderboard });
+        self.broadcast_state().await;
+    }
+
+    /// Pick a random target. `Ok(None)` means there is nothing to pick from.
+    async fn sample_target(&self) -> Result<Option<GameObject>, String> {
+        let (playlist_id, area, hop, used) = {
+            let state = self.state.lock().await;
+            let settings = &state.settings;
+            let hop = state
+                .last_target_location
+                .filter(|_| settings.min_hop_m.is_some() || settings.max_hop_m.is_some())
+                .map(|from| HopRange {
+                    from,
+                    min_m: settings.min_hop_m.unwrap_or(0.0),
+                    max_m: settings.max_hop_m.unwrap_or(f64::INFINITY),
+                });
+            (settings.playlist_id.clone(), state.search_area(), hop, state.used_targets.clone())
+        };
+        let pool = TargetPool { playlist_id: playlist_id.as_deref(), area, hop, used: &used };
+        targets::pick_target(&self.db, &pool).await
     }
 
     async fn start_new_round(&self) {
         tracing::info!("Starting new round - selecting new target");
-        let mut state = self.state.lock().await;
-        let game_objects = self.db.collection::<GameObject>("gameobjects");
-        let pipeline = vec![doc! { "$sample": { "size": 1 } }];
-        let mut cursor = game_objects.aggregate(pipeline).await.unwrap();
-
-        if let Some(result) = cursor.next().await {
-            match result {
-                Ok(doc) => {
-                    match bson::from_document::<GameObject>(doc) {
-                        Ok(target) => {
-                            trace!("Found target object: {:?}", target.name);
-                            state.phase = LobbyPhase::Searching {
-                                target: target.clone(),
-                                scores: HashMap::new(),
-                                zoom_level: 1.0,
-                            };
-
-                            tracing::info!("Emitting NewRound (next) for lobby {}", state.id);
-                            let _ = self.tx.send(GameMessage::NewRound { target });
-                        }
-                        Err(e) => {
-                            tracing::error!("Failed to deserialize target object from bson: {:?}", e);
-                            state.phase = LobbyPhase::WaitingForStart;
-                        }
-                    }
-                }
-                Err(e) => {
-                    tracing::error!("Mongo cursor error during next target fetch: {:?}", e);
-                    state.phase = LobbyPhase::WaitingForStart;
+        {
+            // The host may have left, taking the reported centre of the area with them
+            let mut state = self.state.lock().await;
+            if let Err(message) = state.check_search_area() {
+                tracing::info!("Lobby {} has no search area centre; stopping rounds", state.id);
+                state.phase = LobbyPhase::WaitingForStart;
+                if let Some(host) = &state.host {
+                    self.send_to(host, GameMessage::Error { message });
                 }
+                self.persist(state).await;
+                self.broadcast_state().await;
+                return;
             }
-        } else {
-            // Fallback: no objects in DB. Use a transparent 1x1 PNG so the loop progresses.
-            let fallback = GameObject {
-                id: None,
-                name: "Sample".to_string(),
-                image_b64: "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAQAAAC1HAwCAAAAC0lEQVR42mP8/x8AAwMCAO2nY0kAAAAASUVORK5CYII=".to_string(),
-            };
-            trace!("No game objects found; using fallback");
-            state.phase = LobbyPhase::Searching {
-                target: fallback.clone(),
-                scores: HashMap::new(),
-                zoom_level: 1.0,
-            };
-            let _ = self.tx.send(GameMessage::NewRound { target: fallback });
         }
+        let target = match self.sample_target().await {
+            Ok(Some(target)) => target,
+            Ok(None) => {
+                // Every target has been played (or there were none): end the game here
+                let mut state = self.state.lock().await;
+                let played = state.used_targets.len();
+                tracing::info!("Lobby {} ran out of targets after {}", state.id, played);
+                state.phase = if played == 0 {
+                    LobbyPhase::WaitingForStart
+                } else {
+                    LobbyPhase::GameOver { leaderboard: state.leaderboard() }
+                };
+                self.persist(state).await;
+
+                let _ = self.tx.send(GameMessage::PoolExhausted { played });
+                self.broadcast_state().await;
+                return;
+            }
+            Err(e) => {
+                tracing::error!("{}", e);
+                let mut state = self.state.lock().await;
+                state.phase = LobbyPhase::WaitingForStart;
+                self.persist(state).await;
+                self.broadcast_state().await;
+                return;
+            }
+        };
+        trace!("Found target object: {:?}", target.name);
+
+        // Players only ever see the server-rendered crop, starting fully zoomed in
+        let view = match zoom::render_target_view(target.image_b64.clone(), 1.0).await {
+            Ok(view) => view,
+            Err(e) => {
+                tracing::error!("Failed to render initial view of {:?}: {:?}", target.name, e);
+                String::new()
+            }
+        };
 
-        let lobbies = self.db.collection::<LobbyState>("lobbies");
-        lobbies
-            .replace_one(doc! { "id": state.id.to_string() }, state.clone())
-            .await
-            .unwrap();
+        let mut state = self.state.lock().await;
+        let shown = GameObject { image_b64: view.clone(), ..target.for_searching() };
+        state.round += 1;
+        if let Some(id) = target.id {
+            state.used_targets.push(id.to_hex());
+        }
+        state.last_target_location = target.location;
+        state.phase = LobbyPhase::Searching {
+            target,
+            scores: HashMap::new(),
+            zoom_level: 1.0,
+            view_b64: view,
+            deadline_ms: now_millis() + state.settings.round_secs as i64 * 1000,
+            pending: HashMap::new(),
+            confirmed: Vec::new(),
+            found_at: HashMap::new(),
+        };
+
+        tracing::info!("Emitting NewRound (next) for lobby {}", state.id);
+        let _ = self.tx.send(GameMessage::NewRound { target: shown });
+
+        self.persist(state).await;
 
         self.broadcast_state().await;
     }
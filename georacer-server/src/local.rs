@@ -0,0 +1,276 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use image::GrayImage;
+use image::imageops::FilterType;
+use imageproc::binary_descriptors::brief::{BriefDescriptor, TestPair, brief};
+use imageproc::binary_descriptors::BinaryDescriptor;
+use imageproc::corners::oriented_fast;
+use imageproc::point::Point;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::verifier::{ImageVerifier, Verdict};
+use crate::zoom::decode_image;
+
+/// Images are shrunk to this longest edge before keypoints are extracted
+const FEATURE_EDGE: u32 = 320;
+/// BRIEF patches are 31x31, so keypoints have to sit this far inside the border
+const KEYPOINT_MARGIN: u32 = 20;
+/// Below this on either side an image is too small to find keypoints in
+const MIN_FEATURE_EDGE: u32 = 64;
+const MAX_KEYPOINTS: usize = 300;
+const DESCRIPTOR_BITS: usize = 256;
+/// A keypoint match is only kept when its best candidate beats the runner-up by this
+/// factor, which throws out matches on repetitive texture like windows or tiles
+const MATCH_DISTINCTNESS: f32 = 0.8;
+/// Fixed so the same two images always get the same verdict
+const MATCH_SEED: u64 = 0x6e0_7ace;
+
+/// Thresholds for the local comparison
+#[derive(Debug, Clone)]
+pub struct LocalConfig {
+    /// Perceptual-hash distance (out of 64 bits) at or below which two images match outright
+    pub match_hash_distance: u32,
+    /// Perceptual-hash distance at or above which a guess with few keypoint matches
+    /// is rejected without asking a remote verifier
+    pub reject_hash_distance: u32,
+    /// Largest Hamming distance (out of 256 bits) at which two keypoint descriptors match
+    pub descriptor_distance: u32,
+    /// Share of keypoints that need a match for two images to show the same scene
+    pub min_match_ratio: f32,
+}
+
+impl Default for LocalConfig {
+    fn default() -> Self {
+        Self { match_hash_distance: 10, reject_hash_distance: 24, descriptor_distance: 40, min_match_ratio: 0.25 }
+    }
+}
+
+pub(crate) fn env_parse<T: FromStr>(name: &str) -> Option<T> {
+    dotenvy::var(name).ok().and_then(|s| s.parse().ok())
+}
+
+impl LocalConfig {
+    /// Defaults, overridden by `LOCAL_MATCH_HASH_DISTANCE`, `LOCAL_REJECT_HASH_DISTANCE`,
+    /// `LOCAL_DESCRIPTOR_DISTANCE` and `LOCAL_MIN_MATCH_RATIO` when set
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Some(distance) = env_parse("LOCAL_MATCH_HASH_DISTANCE") {
+            config.match_hash_distance = distance;
+        }
+        if let Some(distance) = env_parse("LOCAL_REJECT_HASH_DISTANCE") {
+            config.reject_hash_distance = distance;
+        }
+        if let Some(distance) = env_parse("LOCAL_DESCRIPTOR_DISTANCE") {
+            config.descriptor_distance = distance;
+        }
+        if let Some(ratio) = env_parse("LOCAL_MIN_MATCH_RATIO") {
+            config.min_match_ratio = ratio;
+        }
+        config
+    }
+}
+
+/// What comparing two images locally found
+#[derive(Debug, Clone, Copy)]
+pub struct Comparison {
+    /// Bits that differ between the perceptual hashes, averaged over aHash and dHash
+    pub hash_distance: u32,
+    /// Share of keypoints in the image with fewer of them that found a match in the other
+    pub match_ratio: f32,
+}
+
+/// Mean hash: one bit per cell of an 8x8 thumbnail, set when the cell is brighter than average
+fn average_hash(gray: &GrayImage) -> u64 {
+    let thumb = image::imageops::resize(gray, 8, 8, FilterType::Triangle);
+    let mean = thumb.pixels().map(|p| p.0[0] as u32).sum::<u32>() / 64;
+    thumb.pixels().enumerate().fold(0, |hash, (i, p)| if p.0[0] as u32 > mean { hash | 1 << i } else { hash })
+}
+
+/// Difference hash: one bit per neighbouring pair in a 9x8 thumbnail, set when brightness rises
+fn difference_hash(gray: &GrayImage) -> u64 {
+    let thumb = image::imageops::resize(gray, 9, 8, FilterType::Triangle);
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            if thumb.get_pixel(x, y).0[0] < thumb.get_pixel(x + 1, y).0[0] {
+                hash |= 1 << (y * 8 + x);
+            }
+        }
+    }
+    hash
+}
+
+/// Count keypoints that are each other's distinct nearest neighbour within `max_distance`
+fn distinct_matches(a: &[BriefDescriptor], b: &[BriefDescriptor], max_distance: u32) -> usize {
+    let distances: Vec<Vec<u32>> = a.iter().map(|da| b.iter().map(|db| da.hamming_distance(db)).collect()).collect();
+    let nearest_in_a = |j: usize| (0..a.len()).min_by_key(|&i| distances[i][j]);
+
+    let mut matches = 0;
+    for (i, row) in distances.iter().enumerate() {
+        let (mut best, mut runner_up) = ((usize::MAX, u32::MAX), u32::MAX);
+        for (j, &distance) in row.iter().enumerate() {
+            if distance < best.1 {
+                runner_up = best.1;
+                best = (j, distance);
+            } else if distance < runner_up {
+                runner_up = distance;
+            }
+        }
+        let (j, distance) = best;
+        if distance <= max_distance
+            && (distance as f32) < MATCH_DISTINCTNESS * runner_up as f32
+            && nearest_in_a(j) == Some(i)
+        {
+            matches += 1;
+        }
+    }
+    matches
+}
+
+/// Compares images with perceptual hashes and BRIEF keypoint matching, entirely in-process
+#[derive(Clone)]
+pub struct LocalVerifier {
+    config: LocalConfig,
+    /// Shared by every descriptor so descriptors from different images are comparable
+    test_pairs: Arc<Vec<TestPair>>,
+}
+
+impl LocalVerifier {
+    pub fn new(config: LocalConfig) -> Self {
+        let mut rng = StdRng::seed_from_u64(MATCH_SEED);
+        let mut point = || Point::new(rng.gen_range(0..31), rng.gen_range(0..31));
+        let test_pairs = (0..DESCRIPTOR_BITS).map(|_| TestPair { p0: point(), p1: point() }).collect();
+        Self { config, test_pairs: Arc::new(test_pairs) }
+    }
+
+    fn descriptors(&self, gray: &GrayImage) -> Vec<BriefDescriptor> {
+        if gray.width() < MIN_FEATURE_EDGE || gray.height() < MIN_FEATURE_EDGE {
+            return Vec::new();
+        }
+        let keypoints: Vec<Point<u32>> = oriented_fast(gray, None, MAX_KEYPOINTS, KEYPOINT_MARGIN, Some(MATCH_SEED))
+            .into_iter()
+            .map(|c| Point::new(c.corner.x, c.corner.y))
+            .collect();
+        match brief(gray, &keypoints, DESCRIPTOR_BITS, Some(&self.test_pairs)) {
+            Ok((descriptors, _)) => descriptors,
+            Err(err) => {
+                tracing::warn!("computing keypoint descriptors: {}", err);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Decode both images and compare them. CPU-bound; call off the async runtime.
+    pub fn compare_blocking(&self, target_b64: &str, submission_b64: &str) -> anyhow::Result<Comparison> {
+        let prepare = |image_b64: &str| -> anyhow::Result<GrayImage> {
+            let image = decode_image(image_b64)?;
+            Ok(image.resize(FEATURE_EDGE, FEATURE_EDGE, FilterType::Triangle).to_luma8())
+        };
+        let target = prepare(target_b64).context("target image")?;
+        let submission = prepare(submission_b64).context("submitted image")?;
+
+        let hash_distance = ((average_hash(&target) ^ average_hash(&submission)).count_ones()
+            + (difference_hash(&target) ^ difference_hash(&submission)).count_ones())
+            / 2;
+
+        let target_features = self.descriptors(&target);
+        let submission_features = self.descriptors(&submission);
+        let fewest = target_features.len().min(submission_features.len());
+        let match_ratio = if fewest == 0 {
+            0.0
+        } else {
+            let matches = distinct_matches(&target_features, &submission_features, self.config.descriptor_distance);
+            matches as f32 / fewest as f32
+        };
+
+        Ok(Comparison { hash_distance, match_ratio })
+    }
+
+    pub async fn compare(&self, target_b64: &str, submission_b64: &str) -> anyhow::Result<Comparison> {
+        let verifier = self.clone();
+        let (target_b64, submission_b64) = (target_b64.to_string(), submission_b64.to_string());
+        tokio::task::spawn_blocking(move || verifier.compare_blocking(&target_b64, &submission_b64))
+            .await
+            .context("comparison task panicked")?
+    }
+
+    /// Too far apart on every measure to be worth a second opinion
+    pub fn is_clear_mismatch(&self, comparison: &Comparison) -> bool {
+        comparison.hash_distance >= self.config.reject_hash_distance
+            && comparison.match_ratio < self.config.min_match_ratio / 2.0
+    }
+
+    /// Turn a comparison into a verdict. Confidence is 0.5 right at a threshold and
+    /// grows towards 1 the further past it the images are.
+    pub fn verdict(&self, comparison: &Comparison) -> Verdict {
+        let LocalConfig { match_hash_distance, min_match_ratio, .. } = self.config;
+        let hash_margin = (match_hash_distance as f32 - comparison.hash_distance as f32) / match_hash_distance.max(1) as f32;
+        let ratio_margin = (comparison.match_ratio - min_match_ratio) / (1.0 - min_match_ratio).max(f32::EPSILON);
+        let matched = comparison.hash_distance <= match_hash_distance || comparison.match_ratio >= min_match_ratio;
+        let margin = if matched {
+            hash_margin.max(ratio_margin)
+        } else {
+            let hash_miss =
+                comparison.hash_distance.saturating_sub(match_hash_distance) as f32 / (64 - match_hash_distance.min(63)) as f32;
+            let ratio_miss = (min_match_ratio - comparison.match_ratio) / min_match_ratio.max(f32::EPSILON);
+            hash_miss.min(ratio_miss)
+        };
+        Verdict {
+            matched,
+            confidence: 0.5 + 0.5 * margin.clamp(0.0, 1.0),
+            reason: format!(
+                "perceptual hashes {} bits apart, {:.0}% of keypoints matched",
+                comparison.hash_distance,
+                comparison.match_ratio * 100.0
+            ),
+        }
+    }
+}
+
+#[async_trait]
+impl ImageVerifier for LocalVerifier {
+    async fn verify(&self, target_b64: &str, submission_b64: &str) -> anyhow::Result<Verdict> {
+        let comparison = self.compare(target_b64, submission_b64).await?;
+        Ok(self.verdict(&comparison))
+    }
+}
+
+/// Runs the local comparison first and only asks `remote` when it isn't a clear
+/// mismatch. When `remote` fails, the local verdict is used instead.
+pub struct HybridVerifier {
+    local: LocalVerifier,
+    remote: Arc<dyn ImageVerifier>,
+}
+
+impl HybridVerifier {
+    pub fn new(local: LocalVerifier, remote: Arc<dyn ImageVerifier>) -> Self {
+        Self { local, remote }
+    }
+}
+
+#[async_trait]
+impl ImageVerifier for HybridVerifier {
+    async fn verify(&self, target_b64: &str, submission_b64: &str) -> anyhow::Result<Verdict> {
+        let local = self.local.compare(target_b64, submission_b64).await;
+        if let Ok(comparison) = &local
+            && self.local.is_clear_mismatch(comparison)
+        {
+            return Ok(self.local.verdict(comparison));
+        }
+
+        match self.remote.verify(target_b64, submission_b64).await {
+            Ok(verdict) => Ok(verdict),
+            Err(err) => match local {
+                Ok(comparison) => {
+                    tracing::warn!("remote verifier failed, using local verdict: {:#}", err);
+                    Ok(self.local.verdict(&comparison))
+                }
+                Err(_) => Err(err),
+            },
+        }
+    }
+}
@@ -1,18 +1,29 @@
-use crate::handlers::{register_object, ws_handler, add_image_to_gameobject, submit_guess};
+use crate::handlers::{register_object, ws_handler, add_image_to_gameobject, submit_guess, create_lobby, get_lobby, verifier_stats, create_playlist, list_playlists, get_playlist, update_playlist, delete_playlist};
+use crate::ingest::MAX_IMAGE_BODY_BYTES;
+use crate::models::MAX_REFERENCE_IMAGES;
 use crate::state::AppState;
 use anyhow::Context;
-use axum::{routing::{get, post}, Router, response::IntoResponse};
+use axum::{extract::DefaultBodyLimit, routing::{get, post}, Router, response::IntoResponse};
+use dashmap::DashMap;
 use dotenvy::var;
-use mongodb::Client;
+use mongodb::{Client, IndexModel, bson::doc};
 use tower_http::cors::{Any, CorsLayer};
-use std::{error::Error, sync::Arc};
+use std::{collections::HashMap, error::Error, sync::Arc};
 use tokio::net::TcpListener;
 
+pub mod clip;
 pub mod gemini;
+pub mod local;
 pub mod models;
 pub mod state;
+pub mod verifier;
 pub mod handlers;
+pub mod ingest;
 pub mod feed;
+pub mod lifecycle;
+pub mod lobby;
+pub mod targets;
+pub mod zoom;
 
 async fn fallback() -> impl IntoResponse {
     (axum::http::StatusCode::NOT_FOUND, "Invalid route")
@@ -29,14 +40,59 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let db = mdb.database(&var("MONGO_DB_NAME").expect("need MONGO_DB_NAME!"));
 
     // Global feed that pushes a new guess every 20s
-    let feed = std::sync::Arc::new(crate::feed::Feed::new(db));
+    let feed_playlist = var("FEED_PLAYLIST_ID").ok();
+    if let Some(id) = &feed_playlist {
+        crate::targets::find_playlist(&db, id)
+            .await
+            .map_err(anyhow::Error::msg)?
+            .with_context(|| format!("FEED_PLAYLIST_ID {id} is not a playlist"))?;
+    }
+    let feed = std::sync::Arc::new(crate::feed::Feed::new(db.clone(), feed_playlist.clone(), None));
     feed.spawn_loop(20);
 
+    // Regional feeds only pick objects inside their area
+    let regions = crate::feed::parse_regions(&var("FEED_REGIONS").unwrap_or_default()).map_err(anyhow::Error::msg)?;
+    let mut regional_feeds = HashMap::new();
+    for (name, area) in regions {
+        let regional = std::sync::Arc::new(crate::feed::Feed::new(db.clone(), feed_playlist.clone(), Some(area)));
+        regional.spawn_loop(20);
+        regional_feeds.insert(name, regional);
+    }
+
+    // Lets objects be looked up by where they were photographed
+    db.collection::<crate::models::GameObject>("gameobjects")
+        .create_index(IndexModel::builder().keys(doc! { "location": "2dsphere" }).build())
+        .await
+        .context("creating gameobjects location index")?;
+
+    let verifier = crate::verifier::from_env().context("configuring image verifier")?;
+
     let state = Arc::new(AppState {
         mdb,
         feed,
+        regional_feeds,
+        lobbies: DashMap::new(),
+        verifier,
+        verdict_cache: Arc::new(crate::verifier::VerdictCache::from_env()),
+        verify_concurrency: var("VERIFY_CONCURRENCY")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(4),
+        guess_deadline: std::time::Duration::from_secs(
+            var("GUESS_DEADLINE_SECS").ok().and_then(|s| s.parse().ok()).unwrap_or(20),
+        ),
     });
 
+    // Bring back games that were in flight when the server last stopped
+    crate::lifecycle::rehydrate_lobbies(&state, &db).await.context("rehydrating lobbies")?;
+
+    // Lobbies nobody has touched for this long are shut down and forgotten
+    let lobby_idle_secs = var("LOBBY_IDLE_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(600);
+    crate::lifecycle::spawn_idle_reaper(Arc::clone(&state), std::time::Duration::from_secs(lobby_idle_secs));
+
     let cors = CorsLayer::new()
         .allow_methods(Any)
         .allow_headers(Any)
@@ -45,9 +101,15 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let router = Router::new()
         .route("/", get(async || "georacer-server running"))
         .route("/ws", get(ws_handler))
-        .route("/register", post(register_object))
-        .route("/gameobject/image", post(add_image_to_gameobject))
+        // Photos straight off a phone are well past axum's default 2 MB body limit
+        .route("/register", post(register_object).layer(DefaultBodyLimit::max(MAX_IMAGE_BODY_BYTES * MAX_REFERENCE_IMAGES)))
+        .route("/gameobject/image", post(add_image_to_gameobject).layer(DefaultBodyLimit::max(MAX_IMAGE_BODY_BYTES)))
         .route("/guess", post(submit_guess))
+        .route("/verifier/stats", get(verifier_stats))
+        .route("/playlists", post(create_playlist).get(list_playlists))
+        .route("/playlists/{id}", get(get_playlist).put(update_playlist).delete(delete_playlist))
+        .route("/lobby", post(create_lobby))
+        .route("/lobby/{id}", get(get_lobby))
         .fallback(fallback)
         .with_state(state)
         .layer(cors);
@@ -8,15 +8,247 @@ pub struct GameObjectImage {
     pub image_data: Vec<u8>,
 }
 
+/// Limits on what can be registered with objects and playlists
+pub const MAX_NAME_LEN: usize = 100;
+const MAX_DESCRIPTION_LEN: usize = 1000;
+const MAX_TAGS: usize = 20;
+const MAX_TAG_LEN: usize = 32;
+const MAX_PLAYLIST_LEN: usize = 500;
+/// Images a guess may be checked against per object, primary included; each one can
+/// cost a verifier call
+pub const MAX_REFERENCE_IMAGES: usize = 5;
+/// Smallest and largest radius a search area may have, in metres
+const MIN_AREA_RADIUS_M: f64 = 50.0;
+const MAX_AREA_RADIUS_M: f64 = 50_000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum GeoJsonType {
+    Point,
+}
+
+/// GeoJSON point, the shape MongoDB's `2dsphere` index expects
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct GeoPoint {
+    #[serde(rename = "type")]
+    pub kind: GeoJsonType,
+    /// Longitude then latitude, in degrees
+    pub coordinates: [f64; 2],
+}
+
+impl GeoPoint {
+    pub fn new(lng: f64, lat: f64) -> Self {
+        Self { kind: GeoJsonType::Point, coordinates: [lng, lat] }
+    }
+
+    pub fn lng(&self) -> f64 {
+        self.coordinates[0]
+    }
+
+    pub fn lat(&self) -> f64 {
+        self.coordinates[1]
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(-180.0..=180.0).contains(&self.lng()) {
+            return Err("longitude must be between -180 and 180".to_string());
+        }
+        if !(-90.0..=90.0).contains(&self.lat()) {
+            return Err("latitude must be between -90 and 90".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Circle targets are drawn from, so they are within walking distance
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SearchArea {
+    pub centre: GeoPoint,
+    pub radius_m: f64,
+}
+
+impl SearchArea {
+    pub fn validate(&self) -> Result<(), String> {
+        self.centre.validate()?;
+        if !(MIN_AREA_RADIUS_M..=MAX_AREA_RADIUS_M).contains(&self.radius_m) {
+            return Err(format!("radius_m must be between {MIN_AREA_RADIUS_M} and {MAX_AREA_RADIUS_M}"));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GameObject {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<mongodb::bson::oid::ObjectId>,
     pub name: String,
+    /// Primary image: the one players are shown and the one zoomed into
     pub image_b64: String,
+    /// More photos of the same thing (other angles, seasons, lighting) that a guess may match
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_images: Vec<String>,
+    /// Where the primary image was taken
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<GeoPoint>,
+    /// How far off `location` may be, in metres
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accuracy_m: Option<f64>,
+    /// Where the primary image's EXIF said it was taken, kept after the metadata is
+    /// stripped; set by the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exif_location: Option<GeoPoint>,
+    /// Who registered the object
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creator: Option<String>,
+    /// When the object was registered, in unix millis; set by the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+impl GameObject {
+    /// Every image a guess is checked against, primary first
+    pub fn reference_images(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.image_b64.as_str()).chain(self.extra_images.iter().map(String::as_str))
+    }
+
+    /// Check a newly registered object, trimming its text fields on the way
+    pub fn validate(&mut self) -> Result<(), String> {
+        self.name = self.name.trim().to_string();
+        if self.name.is_empty() || self.name.len() > MAX_NAME_LEN {
+            return Err(format!("name must be 1 to {MAX_NAME_LEN} characters"));
+        }
+        if self.image_b64.trim().is_empty() {
+            return Err("image_b64 is required".to_string());
+        }
+        if self.extra_images.len() >= MAX_REFERENCE_IMAGES {
+            return Err(format!("an object has at most {MAX_REFERENCE_IMAGES} images"));
+        }
+        if let Some(location) = &self.location {
+            location.validate()?;
+        }
+        if let Some(accuracy) = self.accuracy_m
+            && !(accuracy.is_finite() && accuracy >= 0.0)
+        {
+            return Err("accuracy_m must be a non-negative number of metres".to_string());
+        }
+        if self.accuracy_m.is_some() && self.location.is_none() {
+            return Err("accuracy_m needs a location".to_string());
+        }
+        if let Some(creator) = &mut self.creator {
+            *creator = creator.trim().to_string();
+            if creator.is_empty() || creator.len() > MAX_NAME_LEN {
+                return Err(format!("creator must be 1 to {MAX_NAME_LEN} characters"));
+            }
+        }
+        if self.tags.len() > MAX_TAGS {
+            return Err(format!("at most {MAX_TAGS} tags are allowed"));
+        }
+        for tag in &mut self.tags {
+            *tag = tag.trim().to_lowercase();
+            if tag.is_empty() || tag.len() > MAX_TAG_LEN {
+                return Err(format!("tags must be 1 to {MAX_TAG_LEN} characters"));
+            }
+        }
+        self.tags.sort();
+        self.tags.dedup();
+        if let Some(description) = &self.description
+            && description.len() > MAX_DESCRIPTION_LEN
+        {
+            return Err(format!("description must be at most {MAX_DESCRIPTION_LEN} characters"));
+        }
+        Ok(())
+    }
+
+    /// Copy to send to clients, without the extra references
+    pub fn for_display(&self) -> GameObject {
+        GameObject { extra_images: Vec::new(), ..self.clone() }
+    }
+
+    /// Copy to send while the object is still being searched for: like `for_display`,
+    /// minus everything that gives away where it is
+    pub fn for_searching(&self) -> GameObject {
+        GameObject { location: None, accuracy_m: None, exif_location: None, description: None, ..self.for_display() }
+    }
+
+    /// Stable key for caching verdicts against this object: its id and reference count,
+    /// so adding an image invalidates earlier verdicts, or a hash of its image when it
+    /// was never stored
+    pub fn cache_key(&self) -> String {
+        match self.id {
+            Some(id) => format!("{}:{}", id.to_hex(), self.reference_images().count()),
+            None => crate::verifier::image_hash(&self.image_b64),
+        }
+    }
+}
+
+/// Who can find a playlist
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    /// Listed for everyone
+    #[default]
+    Public,
+    /// Not listed, but anyone with the id can use it
+    Unlisted,
+    /// Only shown to its owner
+    Private,
+}
+
+/// A named list of objects that a lobby or the feed draws its targets from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Playlist {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<mongodb::bson::oid::ObjectId>,
+    pub name: String,
+    pub owner: String,
+    /// Ids of the objects in the playlist
+    pub object_ids: Vec<String>,
+    /// Play the objects in list order instead of at random
+    #[serde(default)]
+    pub ordered: bool,
+    #[serde(default)]
+    pub visibility: Visibility,
+    /// When the playlist was created, in unix millis; set by the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<i64>,
+}
+
+impl Playlist {
+    /// Check a playlist sent by a client, trimming its text fields on the way
+    pub fn validate(&mut self) -> Result<(), String> {
+        self.name = self.name.trim().to_string();
+        if self.name.is_empty() || self.name.len() > MAX_NAME_LEN {
+            return Err(format!("name must be 1 to {MAX_NAME_LEN} characters"));
+        }
+        self.owner = self.owner.trim().to_string();
+        if self.owner.is_empty() || self.owner.len() > MAX_NAME_LEN {
+            return Err(format!("owner must be 1 to {MAX_NAME_LEN} characters"));
+        }
+        if self.object_ids.len() > MAX_PLAYLIST_LEN {
+            return Err(format!("a playlist holds at most {MAX_PLAYLIST_LEN} objects"));
+        }
+        if self.object_ids.iter().any(|id| mongodb::bson::oid::ObjectId::parse_str(id).is_err()) {
+            return Err("object_ids must all be valid ids".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn object_oids(&self) -> Vec<mongodb::bson::oid::ObjectId> {
+        self.object_ids.iter().filter_map(|id| mongodb::bson::oid::ObjectId::parse_str(id).ok()).collect()
+    }
+
+    /// Whether `viewer` may see this playlist
+    pub fn visible_to(&self, viewer: Option<&str>) -> bool {
+        self.visibility != Visibility::Private || viewer == Some(self.owner.as_str())
+    }
 }
 
 // Simplified protocol: clients connect and receive periodic Guess messages.
+// Messages are serialized straight away, so the size gap between variants costs nothing
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ServerMessage {
@@ -24,15 +256,583 @@ pub enum ServerMessage {
     Error(String),
 }
 
-// For compatibility with any existing client code that might send messages,
-// keep a minimal ClientMessage (can be expanded later if needed)
+// Messages a client can send over its socket. The global feed ignores everything;
+// lobby sockets act on the rest.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ClientMessage {
     Ping,
+    StartGame,
+    SubmitGuess {
+        image_b64: String,
+        /// When the photo was taken on the device, in unix millis
+        #[serde(default)]
+        captured_at: Option<i64>,
+    },
+    UpdateSettings { settings: LobbySettings },
+    KickPlayer { name: String },
+    SkipTarget,
+    /// Where the player is; the host's position centres the search area when the
+    /// settings don't fix one
+    ReportLocation { location: GeoPoint },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Player {
     pub name: String,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Submission {
+    pub player: Player,
+    pub image_b64: String,
+    /// The round (`LobbyState::round`) the guess was made in
+    pub round: u32,
+    /// When the server received the guess, in unix millis
+    pub received_at: i64,
+    /// When the client says the photo was taken, in unix millis
+    pub captured_at: Option<i64>,
+}
+
+impl Submission {
+    /// The time this guess is ranked by: the client's capture time when it falls within
+    /// `tolerance_ms` before the server received it, otherwise the receive time. Device
+    /// clocks can't move a guess earlier than the tolerance or later than its arrival.
+    pub fn timestamp(&self, tolerance_ms: i64) -> i64 {
+        match self.captured_at {
+            Some(captured_at) => captured_at.clamp(self.received_at - tolerance_ms, self.received_at),
+            None => self.received_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LobbySettings {
+    /// Total score a player needs to win the game
+    pub points_to_win: f32,
+    /// Number of players that can score on a target before the next one is picked
+    pub scorers_per_target: usize,
+    /// Time limit for a single target
+    pub round_secs: u64,
+    /// Seconds between zoom-out steps
+    pub zoom_interval_secs: u64,
+    /// How far the view zooms out at each step
+    pub zoom_step: f32,
+    /// Zoom level at which the reveal stops
+    pub zoom_floor: f32,
+    /// Playlist to draw targets from; `None` uses every object
+    pub playlist_id: Option<String>,
+    /// Only draw targets within this distance of `centre`, in metres; `None` draws from anywhere
+    pub radius_m: Option<f64>,
+    /// Middle of the search area; `None` follows the host's reported location
+    pub centre: Option<GeoPoint>,
+    /// Pick each target at least this far from the previous one, in metres
+    pub min_hop_m: Option<f64>,
+    /// Pick each target at most this far from the previous one, in metres
+    pub max_hop_m: Option<f64>,
+    /// How sure the verifier must be before a guess counts, from 0 to 1
+    pub min_confidence: f32,
+}
+
+impl Default for LobbySettings {
+    fn default() -> Self {
+        Self {
+            points_to_win: 5.0,
+            scorers_per_target: 1,
+            round_secs: 60,
+            zoom_interval_secs: 3,
+            zoom_step: 0.1,
+            zoom_floor: 0.1,
+            playlist_id: None,
+            radius_m: None,
+            centre: None,
+            min_hop_m: None,
+            max_hop_m: None,
+            min_confidence: crate::verifier::DEFAULT_MIN_CONFIDENCE,
+        }
+    }
+}
+
+impl LobbySettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.points_to_win.is_finite() && self.points_to_win > 0.0) {
+            return Err("points_to_win must be positive".to_string());
+        }
+        if self.scorers_per_target == 0 {
+            return Err("scorers_per_target must be at least 1".to_string());
+        }
+        if !(10..=600).contains(&self.round_secs) {
+            return Err("round_secs must be between 10 and 600".to_string());
+        }
+        if self.zoom_interval_secs == 0 {
+            return Err("zoom_interval_secs must be at least 1".to_string());
+        }
+        if !(self.zoom_step > 0.0 && self.zoom_step <= 1.0) {
+            return Err("zoom_step must be in (0, 1]".to_string());
+        }
+        if !(self.zoom_floor > 0.0 && self.zoom_floor <= 1.0) {
+            return Err("zoom_floor must be in (0, 1]".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.min_confidence) {
+            return Err("min_confidence must be between 0 and 1".to_string());
+        }
+        if let Some(id) = &self.playlist_id
+            && mongodb::bson::oid::ObjectId::parse_str(id).is_err()
+        {
+            return Err("playlist_id is not a valid id".to_string());
+        }
+        match (self.radius_m, self.centre) {
+            (Some(radius_m), centre) => {
+                // Any valid point will do when the centre follows the host
+                let centre = centre.unwrap_or(GeoPoint::new(0.0, 0.0));
+                SearchArea { centre, radius_m }.validate()?;
+            }
+            (None, Some(_)) => return Err("centre needs a radius_m".to_string()),
+            (None, None) => {}
+        }
+        for hop in [self.min_hop_m, self.max_hop_m].into_iter().flatten() {
+            if !(hop.is_finite() && hop >= 0.0) {
+                return Err("hop distances must be non-negative numbers of metres".to_string());
+            }
+        }
+        if let (Some(min), Some(max)) = (self.min_hop_m, self.max_hop_m)
+            && min > max
+        {
+            return Err("min_hop_m can't be more than max_hop_m".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Each lobby holds exactly one phase, so the size gap between variants costs nothing
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "phase")]
+pub enum LobbyPhase {
+    WaitingForStart,
+    Countdown,
+    Searching {
+        target: GameObject,
+        scores: HashMap<String, f32>,
+        zoom_level: f32,
+        /// The rendered crop of the target at `zoom_level`. Never stored; players
+        /// receive it in place of the full image (see `LobbyState::redacted`).
+        #[serde(skip)]
+        view_b64: String,
+        /// When the round's time limit runs out, in unix millis
+        #[serde(default)]
+        deadline_ms: i64,
+        /// Players whose guess is still being verified, with its ranking timestamp
+        #[serde(skip)]
+        pending: HashMap<String, i64>,
+        /// Verified finds waiting for earlier guesses to be decided before they score
+        #[serde(skip)]
+        confirmed: Vec<(String, i64)>,
+        /// Ranking timestamp of every find that has scored this round
+        #[serde(default)]
+        found_at: HashMap<String, i64>,
+    },
+    GameOver {
+        /// Final standings, highest score first
+        leaderboard: Vec<(Player, f32)>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LobbyState {
+    pub id: String,
+    /// Players holding a seat, longest-connected first
+    pub players: Vec<Player>,
+    /// Seated players whose socket dropped, with the time (unix millis) it happened.
+    /// They keep their seat until the reconnect grace period runs out.
+    #[serde(default)]
+    pub disconnected: HashMap<String, i64>,
+    /// Resume token -> player name. Persisted so seats survive a restart, but
+    /// stripped from everything sent to players.
+    #[serde(default)]
+    pub resume_tokens: HashMap<String, String>,
+    /// Number of watch-only sockets; spectators never hold a seat
+    #[serde(default)]
+    pub spectators: usize,
+    /// Players the host removed; they can't take a seat again
+    #[serde(default)]
+    pub kicked: Vec<String>,
+    /// Name of the player allowed to start, configure and moderate the lobby
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(flatten)]
+    pub phase: LobbyPhase,
+    /// Counts rounds played so late verification results can't land in a later round
+    #[serde(default)]
+    pub round: u32,
+    /// Objects already used as targets this game, so none comes up twice
+    #[serde(default)]
+    pub used_targets: Vec<String>,
+    /// Where the previous target was photographed, for picking the next one nearby
+    #[serde(default)]
+    pub last_target_location: Option<GeoPoint>,
+    /// Last reported position of each player. Never stored or sent to anyone.
+    #[serde(skip)]
+    pub locations: HashMap<String, GeoPoint>,
+    pub total_scores: HashMap<String, f32>,
+    #[serde(default)]
+    pub settings: LobbySettings,
+}
+
+impl LobbyState {
+    pub fn new(id: String, settings: LobbySettings) -> Self {
+        Self {
+            id,
+            players: Vec::new(),
+            disconnected: HashMap::new(),
+            resume_tokens: HashMap::new(),
+            spectators: 0,
+            kicked: Vec::new(),
+            host: None,
+            phase: LobbyPhase::WaitingForStart,
+            round: 0,
+            used_targets: Vec::new(),
+            last_target_location: None,
+            locations: HashMap::new(),
+            total_scores: HashMap::new(),
+            settings,
+        }
+    }
+
+    /// Copy of the state that is safe to send to players: the target carries the
+    /// current zoomed view instead of the full image and nothing about where it is,
+    /// and resume tokens are dropped.
+    pub fn redacted(&self) -> LobbyState {
+        let mut state = self.clone();
+        state.resume_tokens.clear();
+        // This is where the current target was photographed until the next round starts
+        state.last_target_location = None;
+        if let LobbyPhase::Searching { target, view_b64, .. } = &mut state.phase {
+            *target = GameObject { image_b64: std::mem::take(view_b64), ..target.for_searching() };
+        }
+        state
+    }
+
+    /// Where targets should come from: around the fixed centre, or around the host
+    /// once they have reported a location. `None` means anywhere.
+    pub fn search_area(&self) -> Option<SearchArea> {
+        let radius_m = self.settings.radius_m?;
+        let centre = self.settings.centre.or_else(|| self.locations.get(self.host.as_ref()?).copied())?;
+        Some(SearchArea { centre, radius_m })
+    }
+
+    /// Targets can't be picked while the lobby asks for a search area that has no
+    /// centre yet, rather than silently drawing them from anywhere
+    pub fn check_search_area(&self) -> Result<(), String> {
+        if self.settings.radius_m.is_some() && self.search_area().is_none() {
+            return Err("The search area needs a centre: set one, or share the host's location first".to_string());
+        }
+        Ok(())
+    }
+
+    /// Seated players that currently have a live socket
+    pub fn connected_players(&self) -> impl Iterator<Item = &Player> {
+        self.players.iter().filter(|p| !self.disconnected.contains_key(&p.name))
+    }
+
+    /// The connected player who has held their seat the longest
+    pub fn next_host(&self) -> Option<String> {
+        self.connected_players().next().map(|p| p.name.clone())
+    }
+
+    /// Why the current round should end early, if it should
+    pub fn round_end_reason(&self) -> Option<RoundEndReason> {
+        let LobbyPhase::Searching { scores, .. } = &self.phase else {
+            return None;
+        };
+        // Only players still connected count towards everyone having found it, and right
+        // after a restart nobody is connected yet, which mustn't count either
+        let connected = self.connected_players().count();
+        let connected_found = self.connected_players().filter(|p| scores.contains_key(&p.name)).count();
+        if scores.is_empty() {
+            None
+        } else if connected > 0 && connected_found >= connected {
+            Some(RoundEndReason::AllFound)
+        } else if scores.len() >= self.settings.scorers_per_target {
+            Some(RoundEndReason::ScorersReached)
+        } else {
+            None
+        }
+    }
+
+    /// Turn verified finds into points, earliest first. A find only scores once every
+    /// guess made before it has been decided (or right away with `force`, used when the
+    /// round ends), so a slow verification can't hand points to a later photo. Finds
+    /// with identical timestamps tie: each ranks only behind strictly earlier finds,
+    /// so they score the same. A player scores at most once per round, and no more than
+    /// `scorers_per_target` finds score in all. Returns who scored,
+    /// in order.
+    pub fn settle_finds(&mut self, force: bool) -> Vec<String> {
+        let seated = self.players.len();
+        let cap = self.settings.scorers_per_target;
+        let LobbyPhase::Searching { scores, pending, confirmed, found_at, .. } = &mut self.phase else {
+            return Vec::new();
+        };
+        confirmed.sort_by_key(|(_, ts)| *ts);
+        let earliest_pending = pending.values().min().copied();
+
+        let mut awarded = Vec::new();
+        while let Some((_, ts)) = confirmed.first() {
+            if !force && earliest_pending.is_some_and(|pending_ts| pending_ts <= *ts) {
+                break;
+            }
+            if found_at.len() >= cap {
+                // The target is used up; later finds, even tied ones, don't score
+                confirmed.clear();
+                break;
+            }
+            let (name, ts) = confirmed.remove(0);
+            if found_at.contains_key(&name) {
+                continue;
+            }
+            // One point for every seated player this find beat
+            let rank = found_at.values().filter(|t| **t < ts).count();
+            let points = seated.saturating_sub(rank + 1) as f32;
+            scores.insert(name.clone(), points);
+            found_at.insert(name.clone(), ts);
+            *self.total_scores.entry(name.clone()).or_insert(0.0) += points;
+            awarded.push(name);
+        }
+        awarded
+    }
+
+    /// Rank everyone who has played or scored by total score, ties broken by name
+    pub fn leaderboard(&self) -> Vec<(Player, f32)> {
+        let mut standings: HashMap<String, f32> = self
+            .players
+            .iter()
+            .map(|p| (p.name.clone(), 0.0))
+            .collect();
+        standings.extend(self.total_scores.iter().map(|(name, score)| (name.clone(), *score)));
+
+        let mut leaderboard: Vec<(Player, f32)> = standings
+            .into_iter()
+            .map(|(name, score)| (Player { name }, score))
+            .collect();
+        leaderboard.sort_by(|(a, a_score), (b, b_score)| b_score.total_cmp(a_score).then_with(|| a.name.cmp(&b.name)));
+        leaderboard
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RoundEndReason {
+    TimeUp,
+    ScorersReached,
+    AllFound,
+    Skipped,
+}
+
+// Messages broadcast to every socket in a lobby.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum GameMessage {
+    GameState(Box<LobbyState>),
+    Countdown { duration: u8 },
+    NewRound { target: GameObject },
+    UpdateImage { zoom_level: f32, image_b64: String },
+    Tick { seconds_left: u32, submitted: usize, active: usize },
+    /// Sent only to the player who guessed
+    GuessResult { correct: bool, confidence: f32, reason: String },
+    /// Sent only to the player who guessed when the guess could not be checked;
+    /// nothing was recorded, so they can submit again
+    VerificationUnavailable { reason: String },
+    /// Every target in the lobby's pool has been played, so the game ends without
+    /// a winner; `played` is how many there were
+    PoolExhausted { played: usize },
+    /// Ends a round and reveals the full target image
+    RoundOver { scores: HashMap<String, f32>, reason: RoundEndReason, target: GameObject },
+    GameOver { winner: Player, leaderboard: Vec<(Player, f32)> },
+    /// Sent only to a socket that just joined; the token lets it reclaim its seat after a drop
+    Joined { player: Player, resume_token: String },
+    /// Sent only to the socket whose request was rejected
+    Error { message: String },
+    /// Sent only to a player the host removed; their socket is closed afterwards
+    Kicked,
+    /// The lobby was shut down; every socket is closed afterwards
+    LobbyClosed,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lobby_with(players: &[&str]) -> LobbyState {
+        let mut state = LobbyState::new("test".to_string(), LobbySettings { scorers_per_target: 3, ..Default::default() });
+        state.players = players.iter().map(|name| Player { name: name.to_string() }).collect();
+        state.phase = LobbyPhase::Searching {
+            target: GameObject {
+                id: None,
+                name: "target".to_string(),
+                image_b64: String::new(),
+                extra_images: Vec::new(),
+                location: None,
+                accuracy_m: None,
+                exif_location: None,
+                creator: None,
+                created_at: None,
+                tags: Vec::new(),
+                description: None,
+            },
+            scores: HashMap::new(),
+            zoom_level: 1.0,
+            view_b64: String::new(),
+            deadline_ms: 0,
+            pending: HashMap::new(),
+            confirmed: Vec::new(),
+            found_at: HashMap::new(),
+        };
+        state
+    }
+
+    fn score(state: &mut LobbyState, name: &str) {
+        if let LobbyPhase::Searching { scores, .. } = &mut state.phase {
+            scores.insert(name.to_string(), 1.0);
+        }
+    }
+
+    fn confirm(state: &mut LobbyState, name: &str, ts: i64) {
+        if let LobbyPhase::Searching { confirmed, .. } = &mut state.phase {
+            confirmed.push((name.to_string(), ts));
+        }
+    }
+
+    #[test]
+    fn finds_score_one_point_per_player_beaten() {
+        let mut state = lobby_with(&["ann", "bob", "cat"]);
+        confirm(&mut state, "bob", 200);
+        confirm(&mut state, "ann", 100);
+        assert_eq!(state.settle_finds(false), ["ann", "bob"]);
+        assert_eq!(state.total_scores["ann"], 2.0);
+        assert_eq!(state.total_scores["bob"], 1.0);
+    }
+
+    #[test]
+    fn simultaneous_finds_tie() {
+        let mut state = lobby_with(&["ann", "bob", "cat"]);
+        confirm(&mut state, "ann", 100);
+        confirm(&mut state, "bob", 100);
+        confirm(&mut state, "cat", 150);
+        state.settle_finds(false);
+        assert_eq!(state.total_scores["ann"], 2.0);
+        assert_eq!(state.total_scores["bob"], 2.0);
+        assert_eq!(state.total_scores["cat"], 0.0);
+    }
+
+    #[test]
+    fn finds_wait_for_earlier_pending_guesses() {
+        let mut state = lobby_with(&["ann", "bob"]);
+        if let LobbyPhase::Searching { pending, .. } = &mut state.phase {
+            pending.insert("ann".to_string(), 100);
+        }
+        confirm(&mut state, "bob", 200);
+        assert!(state.settle_finds(false).is_empty());
+        assert_eq!(state.settle_finds(true), ["bob"]);
+        assert_eq!(state.total_scores["bob"], 1.0);
+    }
+
+    #[test]
+    fn a_player_scores_once_per_round() {
+        let mut state = lobby_with(&["ann", "bob", "cat"]);
+        confirm(&mut state, "ann", 100);
+        confirm(&mut state, "ann", 120);
+        assert_eq!(state.settle_finds(false), ["ann"]);
+        confirm(&mut state, "ann", 300);
+        assert!(state.settle_finds(false).is_empty());
+        assert_eq!(state.total_scores["ann"], 2.0);
+    }
+
+    #[test]
+    fn only_scorers_per_target_finds_score() {
+        let mut state = lobby_with(&["ann", "bob", "cat"]);
+        state.settings.scorers_per_target = 1;
+        if let LobbyPhase::Searching { pending, .. } = &mut state.phase {
+            pending.insert("ann".to_string(), 100);
+        }
+        confirm(&mut state, "bob", 200);
+        confirm(&mut state, "cat", 300);
+        assert!(state.settle_finds(false).is_empty());
+
+        // ann's verdict arrives: her find is first and takes the only slot
+        if let LobbyPhase::Searching { pending, .. } = &mut state.phase {
+            pending.clear();
+        }
+        confirm(&mut state, "ann", 100);
+        assert_eq!(state.settle_finds(false), ["ann"]);
+        assert!(state.settle_finds(true).is_empty());
+        assert_eq!(state.total_scores.len(), 1);
+    }
+
+    #[test]
+    fn capture_time_is_clamped_to_the_tolerance() {
+        let submission = |captured_at| Submission {
+            player: Player { name: "ann".to_string() },
+            image_b64: String::new(),
+            round: 1,
+            received_at: 10_000,
+            captured_at,
+        };
+        assert_eq!(submission(None).timestamp(3000), 10_000);
+        assert_eq!(submission(Some(9_000)).timestamp(3000), 9_000);
+        assert_eq!(submission(Some(1_000)).timestamp(3000), 7_000);
+        assert_eq!(submission(Some(20_000)).timestamp(3000), 10_000);
+    }
+
+    #[test]
+    fn live_target_hides_its_location() {
+        let mut state = lobby_with(&["ann"]);
+        if let LobbyPhase::Searching { target, view_b64, .. } = &mut state.phase {
+            target.location = Some(GeoPoint::new(-97.1, 33.2));
+            target.accuracy_m = Some(5.0);
+            target.exif_location = target.location;
+            target.description = Some("by the fountain".to_string());
+            *view_b64 = "view".to_string();
+        }
+        state.last_target_location = Some(GeoPoint::new(-97.1, 33.2));
+        let redacted = state.redacted();
+        assert!(redacted.last_target_location.is_none());
+        let LobbyPhase::Searching { target, .. } = redacted.phase else {
+            panic!("still searching");
+        };
+        assert_eq!(target.image_b64, "view");
+        assert!(target.location.is_none() && target.accuracy_m.is_none() && target.exif_location.is_none());
+        assert!(target.description.is_none());
+    }
+
+    #[test]
+    fn a_radius_needs_a_centre() {
+        let mut state = lobby_with(&["ann"]);
+        state.host = Some("ann".to_string());
+        assert!(state.check_search_area().is_ok());
+        state.settings.radius_m = Some(500.0);
+        assert!(state.check_search_area().is_err());
+        state.locations.insert("ann".to_string(), GeoPoint::new(-97.1, 33.2));
+        assert!(state.check_search_area().is_ok());
+        state.locations.clear();
+        state.settings.centre = Some(GeoPoint::new(-97.1, 33.2));
+        assert!(state.check_search_area().is_ok());
+    }
+
+    #[test]
+    fn round_keeps_going_while_nobody_is_connected() {
+        let mut state = lobby_with(&["ann", "bob"]);
+        score(&mut state, "ann");
+        state.disconnected.insert("ann".to_string(), 0);
+        state.disconnected.insert("bob".to_string(), 0);
+        assert_eq!(state.round_end_reason(), None);
+
+        // ann found it and left; bob is still looking
+        state.disconnected.remove("bob");
+        assert_eq!(state.round_end_reason(), None);
+
+        state.disconnected.remove("ann");
+        assert_eq!(state.round_end_reason(), None);
+        score(&mut state, "bob");
+        assert_eq!(state.round_end_reason(), Some(RoundEndReason::AllFound));
+    }
+}
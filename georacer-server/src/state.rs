@@ -1,8 +1,33 @@
 use crate::feed::Feed;
+use crate::lobby::Lobby;
+use crate::verifier::{ImageVerifier, VerdictCache};
+use dashmap::DashMap;
 use mongodb::Client;
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 pub struct AppState {
     pub mdb: Client,
     pub feed: Arc<Feed>,
+    /// Feeds limited to a named area, from `FEED_REGIONS`
+    pub regional_feeds: HashMap<String, Arc<Feed>>,
+    pub lobbies: DashMap<String, Lobby>,
+    pub verifier: Arc<dyn ImageVerifier>,
+    /// Verdicts shared by every lobby and the feed
+    pub verdict_cache: Arc<VerdictCache>,
+    /// How many guesses each lobby may have in verification at once
+    pub verify_concurrency: usize,
+    /// Longest one guess may spend in verification
+    pub guess_deadline: Duration,
+}
+
+impl AppState {
+    /// The feed for `region`, or the global one without a region
+    pub fn feed_for(&self, region: Option<&str>) -> Option<Arc<Feed>> {
+        match region {
+            Some(region) => self.regional_feeds.get(region).cloned(),
+            None => Some(Arc::clone(&self.feed)),
+        }
+    }
 }
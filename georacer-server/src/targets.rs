@@ -0,0 +1,231 @@
+use futures_util::StreamExt;
+use rand::seq::SliceRandom;
+use mongodb::Database;
+use mongodb::bson::{self, Document, doc, oid::ObjectId};
+
+use crate::models::{GameObject, GeoPoint, Playlist, SearchArea};
+
+/// Look up a playlist by its id. `Ok(None)` when the id is malformed or unknown.
+pub async fn find_playlist(db: &Database, id: &str) -> Result<Option<Playlist>, String> {
+    let Ok(id) = ObjectId::parse_str(id) else {
+        return Ok(None);
+    };
+    db.collection::<Playlist>("playlists")
+        .find_one(doc! { "_id": id })
+        .await
+        .map_err(|e| format!("Mongo error loading playlist {id}: {e:?}"))
+}
+
+/// Which of `ids` don't name a stored object
+pub async fn missing_objects(db: &Database, ids: &[String]) -> Result<Vec<String>, String> {
+    let oids: Vec<ObjectId> = ids.iter().filter_map(|id| ObjectId::parse_str(id).ok()).collect();
+    let mut cursor = db
+        .collection::<Document>("gameobjects")
+        .find(doc! { "_id": { "$in": &oids } })
+        .projection(doc! { "_id": 1 })
+        .await
+        .map_err(|e| format!("Mongo error checking objects: {e:?}"))?;
+    let mut found = Vec::new();
+    while let Some(Ok(doc)) = cursor.next().await {
+        if let Ok(id) = doc.get_object_id("_id") {
+            found.push(id.to_hex());
+        }
+    }
+    Ok(ids.iter().filter(|id| !found.contains(id)).cloned().collect())
+}
+
+/// Mean radius of the Earth in metres for `$centerSphere` area queries; the same radius
+/// the `haversine` crate measures hop distances with
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+/// What the next target may be drawn from
+pub struct TargetPool<'a> {
+    /// Playlist to draw from; `None` means every object
+    pub playlist_id: Option<&'a str>,
+    /// Only objects photographed inside this circle; `None` means anywhere
+    pub area: Option<SearchArea>,
+    /// Keep the next target a walkable hop from the previous one; `None` picks anywhere
+    pub hop: Option<HopRange>,
+    /// Object ids that have already been played
+    pub used: &'a [String],
+}
+
+/// How far the next target should be from the last one
+#[derive(Debug, Clone, Copy)]
+pub struct HopRange {
+    /// Where the previous target was
+    pub from: GeoPoint,
+    pub min_m: f64,
+    pub max_m: f64,
+}
+
+impl HopRange {
+    /// How far outside the range `distance_m` falls; 0 when inside it
+    fn miss_m(&self, distance_m: f64) -> f64 {
+        (self.min_m - distance_m).max(distance_m - self.max_m).max(0.0)
+    }
+}
+
+/// Great-circle (haversine) distance between two points, in metres
+pub fn distance_m(a: &GeoPoint, b: &GeoPoint) -> f64 {
+    let location = |p: &GeoPoint| haversine::Location { latitude: p.lat(), longitude: p.lng() };
+    haversine::distance(location(a), location(b), haversine::Units::Kilometers) * 1000.0
+}
+
+/// Query matching objects inside `area`. Objects without a location never match.
+fn within(area: &SearchArea) -> Document {
+    let centre = vec![area.centre.lng(), area.centre.lat()];
+    doc! { "$geoWithin": { "$centerSphere": [centre, area.radius_m / EARTH_RADIUS_M] } }
+}
+
+/// Pick the next target from `pool` that hasn't been used yet. Ordered playlists give
+/// their first unused entry in the area and ignore `hop`, since their order is already
+/// the route. `Ok(None)` means the pool is used up.
+pub async fn pick_target(db: &Database, pool: &TargetPool<'_>) -> Result<Option<GameObject>, String> {
+    let used: Vec<ObjectId> = pool.used.iter().filter_map(|id| ObjectId::parse_str(id).ok()).collect();
+    let mut filter = doc! { "_id": { "$nin": &used } };
+    if let Some(area) = &pool.area {
+        filter.insert("location", within(area));
+    }
+    let Some(playlist_id) = pool.playlist_id else {
+        return match &pool.hop {
+            Some(hop) => pick_by_hop(db, filter, hop).await,
+            None => sample_one(db, filter).await,
+        };
+    };
+    let playlist = find_playlist(db, playlist_id)
+        .await?
+        .ok_or_else(|| format!("Playlist {playlist_id} no longer exists"))?;
+    let remaining: Vec<ObjectId> = playlist.object_oids().into_iter().filter(|id| !used.contains(id)).collect();
+
+    if !playlist.ordered {
+        filter.insert("_id", doc! { "$in": remaining });
+        return match &pool.hop {
+            Some(hop) => pick_by_hop(db, filter, hop).await,
+            None => sample_one(db, filter).await,
+        };
+    }
+    // Entries whose object has since been deleted or lies outside the area are skipped
+    let game_objects = db.collection::<GameObject>("gameobjects");
+    for id in remaining {
+        filter.insert("_id", id);
+        let target = game_objects
+            .find_one(filter.clone())
+            .await
+            .map_err(|e| format!("Mongo error during next target fetch: {e:?}"))?;
+        if target.is_some() {
+            return Ok(target);
+        }
+    }
+    Ok(None)
+}
+
+/// Pick at random among the objects matching `filter` that lie within `hop` of the
+/// previous target, or the one closest to that range when none do. Objects without
+/// a location are only picked when no object matching `filter` has one.
+async fn pick_by_hop(db: &Database, filter: Document, hop: &HopRange) -> Result<Option<GameObject>, String> {
+    let mut located = filter.clone();
+    if !located.contains_key("location") {
+        located.insert("location", doc! { "$exists": true });
+    }
+    let mut cursor = db
+        .collection::<Document>("gameobjects")
+        .find(located)
+        .projection(doc! { "_id": 1, "location": 1 })
+        .await
+        .map_err(|e| format!("Mongo error listing target locations: {e:?}"))?;
+    let mut candidates: Vec<(ObjectId, f64)> = Vec::new();
+    while let Some(Ok(doc)) = cursor.next().await {
+        let Ok(id) = doc.get_object_id("_id") else { continue };
+        let Some(Ok(location)) = doc.get("location").map(|l| bson::from_bson::<GeoPoint>(l.clone())) else {
+            continue;
+        };
+        candidates.push((id, distance_m(&hop.from, &location)));
+    }
+
+    let Some(id) = choose_by_hop(&candidates, hop) else {
+        return sample_one(db, filter).await;
+    };
+    db.collection::<GameObject>("gameobjects")
+        .find_one(doc! { "_id": id })
+        .await
+        .map_err(|e| format!("Mongo error during next target fetch: {e:?}"))
+}
+
+/// A random candidate inside `hop`, else the one that misses it by the least.
+/// `candidates` pairs each object with its distance from the previous target.
+fn choose_by_hop(candidates: &[(ObjectId, f64)], hop: &HopRange) -> Option<ObjectId> {
+    let in_range: Vec<ObjectId> =
+        candidates.iter().filter(|(_, distance)| hop.miss_m(*distance) == 0.0).map(|(id, _)| *id).collect();
+    match in_range.choose(&mut rand::thread_rng()) {
+        Some(id) => Some(*id),
+        None => candidates.iter().min_by(|a, b| hop.miss_m(a.1).total_cmp(&hop.miss_m(b.1))).map(|(id, _)| *id),
+    }
+}
+
+async fn sample_one(db: &Database, filter: Document) -> Result<Option<GameObject>, String> {
+    let game_objects = db.collection::<GameObject>("gameobjects");
+    let pipeline = vec![doc! { "$match": filter }, doc! { "$sample": { "size": 1 } }];
+    let mut cursor = game_objects
+        .aggregate(pipeline)
+        .await
+        .map_err(|e| format!("Mongo aggregate error during next target fetch: {e:?}"))?;
+
+    match cursor.next().await {
+        Some(Ok(doc)) => bson::from_document::<GameObject>(doc)
+            .map(Some)
+            .map_err(|e| format!("Failed to deserialize target object from bson: {e:?}")),
+        Some(Err(e)) => Err(format!("Mongo cursor error during next target fetch: {e:?}")),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hop(min_m: f64, max_m: f64) -> HopRange {
+        HopRange { from: GeoPoint::new(0.0, 0.0), min_m, max_m }
+    }
+
+    #[test]
+    fn distance_is_in_metres() {
+        let a = GeoPoint::new(-77.037852, 38.898556);
+        let b = GeoPoint::new(-77.043934, 38.897147);
+        assert!((distance_m(&a, &b) - 549.156).abs() < 0.01);
+        assert_eq!(distance_m(&a, &a), 0.0);
+        // One degree of longitude along the equator
+        let degree = distance_m(&GeoPoint::new(0.0, 0.0), &GeoPoint::new(1.0, 0.0));
+        assert!((degree - EARTH_RADIUS_M.to_radians()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn miss_is_the_distance_outside_the_range() {
+        let range = hop(200.0, 800.0);
+        assert_eq!(range.miss_m(50.0), 150.0);
+        assert_eq!(range.miss_m(200.0), 0.0);
+        assert_eq!(range.miss_m(500.0), 0.0);
+        assert_eq!(range.miss_m(800.0), 0.0);
+        assert_eq!(range.miss_m(1000.0), 200.0);
+    }
+
+    #[test]
+    fn hop_picks_within_range_when_it_can() {
+        let (near, inside, far) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+        let candidates = [(near, 10.0), (inside, 500.0), (far, 5000.0)];
+        for _ in 0..20 {
+            assert_eq!(choose_by_hop(&candidates, &hop(200.0, 800.0)), Some(inside));
+        }
+    }
+
+    #[test]
+    fn hop_falls_back_to_the_closest_to_range() {
+        let (near, far) = (ObjectId::new(), ObjectId::new());
+        // Missing by 150 m short of the range beats 300 m past it, and loses to 100 m past it
+        let candidates = [(far, 1100.0), (near, 50.0)];
+        assert_eq!(choose_by_hop(&candidates, &hop(200.0, 800.0)), Some(near));
+        let candidates = [(far, 900.0), (near, 50.0)];
+        assert_eq!(choose_by_hop(&candidates, &hop(200.0, 800.0)), Some(far));
+        assert_eq!(choose_by_hop(&[], &hop(200.0, 800.0)), None);
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use base64::Engine;
+use dashmap::DashMap;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::clip::ClipVerifier;
+use crate::gemini::{GeminiVerifier, parse_data_url};
+use crate::local::{HybridVerifier, LocalConfig, LocalVerifier};
+
+/// Confidence a match needs when nothing more specific is configured
+pub const DEFAULT_MIN_CONFIDENCE: f32 = 0.5;
+
+/// A verifier's judgement on one guess
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Verdict {
+    /// Whether the verifier thinks both images show the same thing
+    pub matched: bool,
+    /// How sure it is of `matched`, from 0 to 1
+    pub confidence: f32,
+    /// Short explanation that can be shown to the player
+    pub reason: String,
+}
+
+impl Verdict {
+    /// A match only counts when the verifier is at least `min_confidence` sure of it
+    pub fn accepted(&self, min_confidence: f32) -> bool {
+        self.matched && self.confidence >= min_confidence
+    }
+}
+
+/// Decides whether a player's photo shows the same thing as a target image
+#[async_trait]
+pub trait ImageVerifier: Send + Sync {
+    async fn verify(&self, target_b64: &str, submission_b64: &str) -> anyhow::Result<Verdict>;
+}
+
+/// Check a guess against each of a target's reference images in turn, stopping at the
+/// first accepted match. Without one, the most confident rejection is returned, but only
+/// when every reference was checked: if any failed, the error is returned instead, since
+/// that reference might have matched and the rejection mustn't be cached as final.
+pub async fn verify_any<'a>(
+    verifier: &dyn ImageVerifier,
+    references: impl IntoIterator<Item = &'a str>,
+    submission_b64: &str,
+    min_confidence: f32,
+) -> anyhow::Result<Verdict> {
+    let mut best: Option<Verdict> = None;
+    let mut last_error = None;
+    for reference in references {
+        match verifier.verify(reference, submission_b64).await {
+            Ok(verdict) if verdict.accepted(min_confidence) => return Ok(verdict),
+            Ok(verdict) => {
+                if best.as_ref().is_none_or(|b| verdict.confidence > b.confidence) {
+                    best = Some(verdict);
+                }
+            }
+            Err(err) => last_error = Some(err),
+        }
+    }
+    match (best, last_error) {
+        (_, Some(err)) => Err(err),
+        (Some(verdict), None) => Ok(verdict),
+        (None, None) => anyhow::bail!("target has no reference images"),
+    }
+}
+
+/// `verify_any` bounded by an overall `deadline`, so timeouts and retries across several
+/// references can't keep a guess waiting for minutes
+pub async fn verify_any_within<'a>(
+    deadline: Duration,
+    verifier: &dyn ImageVerifier,
+    references: impl IntoIterator<Item = &'a str>,
+    submission_b64: &str,
+    min_confidence: f32,
+) -> anyhow::Result<Verdict> {
+    tokio::time::timeout(deadline, verify_any(verifier, references, submission_b64, min_confidence))
+        .await
+        .map_err(|_| anyhow::anyhow!("verification took longer than {deadline:?}"))?
+}
+
+/// Hex SHA-256 of the decoded image bytes, so the same photo hashes the same
+/// whether or not it arrives as a data URL
+pub fn image_hash(image_b64: &str) -> String {
+    let (_, data) = parse_data_url(image_b64);
+    let bytes = STANDARD
+        .decode(data.trim())
+        .unwrap_or_else(|_| data.into_bytes());
+    Sha256::digest(&bytes).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Inserts between sweeps of expired cache entries
+const CACHE_SWEEP_EVERY: u64 = 256;
+
+/// Verdicts already given for a (target, submitted image) pair, so a player resubmitting
+/// the same photo doesn't cost another verification. Entries expire after `ttl`. They are
+/// also keyed by the confidence threshold they were checked against, since `verify_any`
+/// stops at the first reference that clears it.
+pub struct VerdictCache {
+    ttl: Duration,
+    entries: DashMap<(String, String, u32), (Verdict, Instant)>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    inserts: AtomicU64,
+}
+
+/// How well the verdict cache is doing, as reported by `GET /verifier/stats`
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Share of lookups answered from the cache, from 0 to 1
+    pub hit_rate: f64,
+    pub entries: usize,
+}
+
+impl VerdictCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: DashMap::new(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            inserts: AtomicU64::new(0),
+        }
+    }
+
+    /// TTL from `VERDICT_CACHE_TTL_SECS`, 15 minutes by default
+    pub fn from_env() -> Self {
+        let secs = dotenvy::var("VERDICT_CACHE_TTL_SECS").ok().and_then(|s| s.parse().ok()).unwrap_or(900);
+        Self::new(Duration::from_secs(secs))
+    }
+
+    /// The cached verdict for `submission_hash` (see `image_hash`) against the target
+    /// identified by `target_key` at `min_confidence`, if it hasn't expired
+    pub fn get(&self, target_key: &str, submission_hash: &str, min_confidence: f32) -> Option<Verdict> {
+        let key = (target_key.to_string(), submission_hash.to_string(), min_confidence.to_bits());
+        let verdict = match self.entries.get(&key) {
+            Some(entry) if entry.1.elapsed() < self.ttl => Some(entry.0.clone()),
+            _ => None,
+        };
+        if verdict.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.entries.remove_if(&key, |_, (_, stored)| stored.elapsed() >= self.ttl);
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        verdict
+    }
+
+    pub fn insert(&self, target_key: String, submission_hash: String, min_confidence: f32, verdict: Verdict) {
+        self.entries.insert((target_key, submission_hash, min_confidence.to_bits()), (verdict, Instant::now()));
+        if self.inserts.fetch_add(1, Ordering::Relaxed) % CACHE_SWEEP_EVERY == CACHE_SWEEP_EVERY - 1 {
+            self.entries.retain(|_, (_, stored)| stored.elapsed() < self.ttl);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let lookups = hits + misses;
+        CacheStats {
+            hits,
+            misses,
+            hit_rate: if lookups == 0 { 0.0 } else { hits as f64 / lookups as f64 },
+            entries: self.entries.len(),
+        }
+    }
+}
+
+/// Deterministic verifier for local development and tests; never touches the network
+pub enum MockVerifier {
+    AlwaysYes,
+    AlwaysNo,
+    /// Verdicts keyed by the submission's `image_hash`, with a fallback for unknown images
+    Scripted { verdicts: HashMap<String, bool>, default: bool },
+}
+
+#[derive(Deserialize)]
+struct VerdictScript {
+    #[serde(default)]
+    default: bool,
+    verdicts: HashMap<String, bool>,
+}
+
+impl MockVerifier {
+    /// Load a scripted verifier from a JSON file shaped like
+    /// `{ "default": false, "verdicts": { "<sha256>": true } }`
+    pub fn from_script(path: &str) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(path).with_context(|| format!("reading verifier script {path}"))?;
+        let script: VerdictScript = serde_json::from_str(&raw).context("parsing verifier script")?;
+        Ok(MockVerifier::Scripted { verdicts: script.verdicts, default: script.default })
+    }
+}
+
+#[async_trait]
+impl ImageVerifier for MockVerifier {
+    async fn verify(&self, _target_b64: &str, submission_b64: &str) -> anyhow::Result<Verdict> {
+        let matched = match self {
+            MockVerifier::AlwaysYes => true,
+            MockVerifier::AlwaysNo => false,
+            MockVerifier::Scripted { verdicts, default } => {
+                *verdicts.get(&image_hash(submission_b64)).unwrap_or(default)
+            }
+        };
+        Ok(Verdict { matched, confidence: 1.0, reason: "mock verifier".to_string() })
+    }
+}
+
+/// Build the verifier named by `IMAGE_VERIFIER`: `gemini` by default, `local` for the
+/// offline comparison, `hybrid` for Gemini behind the local pre-filter and fallback,
+/// `clip` for the ONNX embedding model at `CLIP_MODEL_PATH`, or
+/// `always-yes`, `always-no`, `scripted` with `IMAGE_VERIFIER_SCRIPT` pointing at the script
+pub fn from_env() -> anyhow::Result<Arc<dyn ImageVerifier>> {
+    let kind = dotenvy::var("IMAGE_VERIFIER").unwrap_or_else(|_| "gemini".to_string());
+    let verifier: Arc<dyn ImageVerifier> = match kind.as_str() {
+        "gemini" => Arc::new(GeminiVerifier::from_env()?),
+        "clip" => Arc::new(ClipVerifier::from_env()?),
+        "local" => Arc::new(LocalVerifier::new(LocalConfig::from_env())),
+        "hybrid" => Arc::new(HybridVerifier::new(
+            LocalVerifier::new(LocalConfig::from_env()),
+            Arc::new(GeminiVerifier::from_env()?),
+        )),
+        "always-yes" => Arc::new(MockVerifier::AlwaysYes),
+        "always-no" => Arc::new(MockVerifier::AlwaysNo),
+        "scripted" => {
+            let path = dotenvy::var("IMAGE_VERIFIER_SCRIPT").context("IMAGE_VERIFIER=scripted needs IMAGE_VERIFIER_SCRIPT")?;
+            Arc::new(MockVerifier::from_script(&path)?)
+        }
+        other => anyhow::bail!("unknown IMAGE_VERIFIER {other:?}"),
+    };
+    tracing::info!("using {} image verifier", kind);
+    Ok(verifier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verdict(matched: bool, confidence: f32) -> Verdict {
+        Verdict { matched, confidence, reason: String::new() }
+    }
+
+    /// Matches only the reference named "match" and fails on the one named "down"
+    struct ByReference;
+
+    #[async_trait]
+    impl ImageVerifier for ByReference {
+        async fn verify(&self, target_b64: &str, _submission_b64: &str) -> anyhow::Result<Verdict> {
+            match target_b64 {
+                "down" => anyhow::bail!("verifier unreachable"),
+                "match" => Ok(verdict(true, 0.9)),
+                _ => Ok(verdict(false, 0.8)),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn any_accepted_reference_matches() {
+        let result = verify_any(&ByReference, ["other", "down", "match"], "photo", 0.5).await.unwrap();
+        assert!(result.matched);
+    }
+
+    #[tokio::test]
+    async fn a_failed_reference_makes_a_rejection_an_error() {
+        assert!(verify_any(&ByReference, ["other", "down"], "photo", 0.5).await.is_err());
+        let rejected = verify_any(&ByReference, ["other", "other"], "photo", 0.5).await.unwrap();
+        assert!(!rejected.matched);
+    }
+
+    #[test]
+    fn cached_verdicts_are_kept_per_threshold() {
+        let cache = VerdictCache::new(Duration::from_secs(60));
+        cache.insert("target".to_string(), "photo".to_string(), 0.5, verdict(true, 0.6));
+        assert!(cache.get("target", "photo", 0.5).is_some());
+        assert!(cache.get("target", "photo", 0.9).is_none());
+    }
+}
@@ -0,0 +1,53 @@
+use anyhow::Context;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use image::codecs::jpeg::JpegEncoder;
+use image::DynamicImage;
+
+use crate::gemini::parse_data_url;
+
+/// Longest edge of a rendered view; the crop is scaled down to this before encoding
+const MAX_VIEW_EDGE: u32 = 512;
+const VIEW_JPEG_QUALITY: u8 = 80;
+
+/// Decode a (possibly data URL) base64 image
+pub fn decode_image(image_b64: &str) -> anyhow::Result<DynamicImage> {
+    let (_, data) = parse_data_url(image_b64);
+    let bytes = STANDARD.decode(data.trim()).context("decoding base64 image")?;
+    image::load_from_memory(&bytes).context("decoding image data")
+}
+
+/// Fraction of the image's width and height that is visible at `zoom_level`.
+/// A zoom level of 1.0 is the tightest crop; as it falls the view widens until
+/// the whole image shows at 0.1.
+pub fn visible_fraction(zoom_level: f32) -> f32 {
+    (1.1 - zoom_level).clamp(0.1, 1.0)
+}
+
+/// Render the centred crop of `image` seen at `zoom_level` as a JPEG data URL.
+/// Only this view is ever sent to players, so the full target can't be read off the socket.
+pub fn render_view(image: &DynamicImage, zoom_level: f32) -> anyhow::Result<String> {
+    let fraction = visible_fraction(zoom_level);
+    let width = ((image.width() as f32 * fraction).round() as u32).max(1);
+    let height = ((image.height() as f32 * fraction).round() as u32).max(1);
+    let x = (image.width() - width) / 2;
+    let y = (image.height() - height) / 2;
+
+    let mut view = image.crop_imm(x, y, width, height);
+    if view.width() > MAX_VIEW_EDGE || view.height() > MAX_VIEW_EDGE {
+        view = view.resize(MAX_VIEW_EDGE, MAX_VIEW_EDGE, image::imageops::FilterType::Triangle);
+    }
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, VIEW_JPEG_QUALITY)
+        .encode_image(&view.to_rgb8())
+        .context("encoding view")?;
+    Ok(format!("data:image/jpeg;base64,{}", STANDARD.encode(jpeg)))
+}
+
+/// Decode and render in one go, off the async runtime
+pub async fn render_target_view(image_b64: String, zoom_level: f32) -> anyhow::Result<String> {
+    tokio::task::spawn_blocking(move || render_view(&decode_image(&image_b64)?, zoom_level))
+        .await
+        .context("render task panicked")?
+}